@@ -0,0 +1,25 @@
+#![allow(clippy::needless_return)]
+#![allow(clippy::unused_unit)]
+
+use std::{hint::black_box, path::Path};
+
+use caedan::interpreter::program::CompiledProgram;
+use criterion::{criterion_group, criterion_main, Criterion};
+
+// Each iteration runs on a freshly instantiated program, so parsing is left out but resetting the regions isn't
+fn run(criterion: &mut Criterion, name: &str, program: &CompiledProgram) -> () {
+    criterion.bench_function(name, |bencher| bencher.iter(|| black_box(program.instantiate().run_with_bytes(&[]).unwrap())));
+}
+
+fn dispatch(criterion: &mut Criterion) -> () {
+    // Almost nothing but loop brackets and single cell arithmetic, which is where the dispatch itself shows
+    let loops: CompiledProgram = CompiledProgram::from_string("region main[3]; proc main: -[>-[>+<-]<-] ;").unwrap();
+    run(criterion, "nested loops", &loops);
+    let calls: CompiledProgram = CompiledProgram::from_string("region main[2]; proc main: -[> step <-] ; proc step: + ;").unwrap();
+    run(criterion, "calls", &calls);
+    let math: CompiledProgram = CompiledProgram::from_source(&Path::new(env!("CARGO_MANIFEST_DIR")).join("examples/math.cae")).unwrap();
+    run(criterion, "math example", &math);
+}
+
+criterion_group!(benches, dispatch);
+criterion_main!(benches);
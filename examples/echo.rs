@@ -0,0 +1,10 @@
+#![allow(clippy::needless_return)]
+#![allow(clippy::unused_unit)]
+
+use caedan::interpreter::program::Program;
+
+fn main() -> () {
+    let mut program: Program = Program::from_string(include_str!("echo.cae")).unwrap();
+    let output: Vec<u8> = program.run_with_bytes(b"Hello, world!\n").unwrap();
+    print!("{}", String::from_utf8_lossy(&output));
+}
@@ -0,0 +1,209 @@
+use std::{collections::HashSet, io::{self, BufRead, Write}};
+
+use caedan::{interpreter::program::{Program, RuntimeError, StackFrame}, region::RegionView};
+
+// The program and the prompt share the input, so both go through the one buffered handle to keep them from stealing
+// each other's input
+struct Debugger<'a> {
+    program: &'a mut Program,
+    input: &'a mut dyn BufRead,
+    output: &'a mut dyn Write,
+    breakpoints: HashSet<(String, usize)>,
+    watches: Vec<Watch>,
+}
+
+// A watched cell, along with the value it had after the last step
+struct Watch {
+    region: String,
+    index: usize,
+    value: u8,
+}
+
+fn parse_location(location: &str) -> Option<(&str, usize)> {
+    let (name, index) = location.rsplit_once(':')?;
+    return Some((name, index.parse::<usize>().ok()?));
+}
+
+fn describe_frame(program: &Program, frame: &StackFrame) -> String {
+    let instruction: String = match program.instruction_at(&frame.procedure, frame.pointer) {
+        Some(instruction) => format!("{:?}", instruction),
+        None => "end of procedure".to_string(),
+    };
+    return format!("{}:{} on {} ({})", frame.procedure, frame.pointer, frame.region, instruction);
+}
+
+// Everything past the last non-zero byte is left out unless the pointer is out there, since most regions are mostly
+// empty
+pub fn print_region(output: &mut dyn Write, region: &RegionView) -> io::Result<()> {
+    writeln!(output, "{} ({} bytes, pointer at {})", region.name, region.bytes.len(), region.pointer)?;
+    let end: usize = region.bytes.iter().rposition(|byte| *byte != 0).map_or(0, |last| last + 1).max(region.pointer + 1);
+    for (row, chunk) in region.bytes[..end].chunks(16).enumerate() {
+        let cells: Vec<String> = chunk.iter().enumerate().map(|(i, byte)| {
+            if (row * 16) + i == region.pointer {
+                return format!("[{:02x}]", byte);
+            }
+            return format!(" {:02x} ", byte);
+        }).collect();
+        writeln!(output, "{:>8}: {}", row * 16, cells.join(""))?;
+    }
+    return Ok(());
+}
+
+impl Debugger<'_> {
+    fn stop(&mut self) -> Result<(), RuntimeError> {
+        match self.program.call_stack().back() {
+            Some(frame) => writeln!(self.output, "{}", describe_frame(self.program, frame)),
+            None => writeln!(self.output, "program finished"),
+        }.map_err(RuntimeError::Io)?;
+        return Ok(());
+    }
+
+    fn cell(&mut self, region: &str, index: usize) -> Option<u8> {
+        return self.program.region_view(region)?.bytes.get(index).copied();
+    }
+
+    // Returns whether the program is still running, and whether any watched cell changed. Only the watched cells are
+    // looked at, so stepping stays cheap however large the regions are
+    fn step(&mut self) -> Result<(bool, bool), RuntimeError> {
+        let location: Option<String> = match self.watches.is_empty() {
+            true => None,
+            false => self.program.call_stack().back().map(|frame| describe_frame(self.program, frame)),
+        };
+        let running: bool = self.program.step_with_io(&mut self.input, &mut self.output)?;
+        let mut changed: bool = false;
+        for watch in &mut self.watches {
+            let Some(value) = self.program.region_view(&watch.region).and_then(|region| region.bytes.get(watch.index).copied()) else {
+                continue;
+            };
+            if value != watch.value {
+                writeln!(self.output, "{}:{} changed {:02x} -> {:02x} at {}", watch.region, watch.index, watch.value, value, location.as_deref().unwrap_or("unknown")).map_err(RuntimeError::Io)?;
+                watch.value = value;
+                changed = true;
+            }
+        }
+        return Ok((running, changed));
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        return self.program.call_stack().back()
+            .is_some_and(|frame| self.breakpoints.contains(&(frame.procedure.clone(), frame.pointer)));
+    }
+
+    fn execute(&mut self, command: &str, argument: Option<&str>) -> Result<(), RuntimeError> {
+        match (command, argument) {
+            ("step" | "s", None) => {
+                self.step()?;
+                self.stop()?;
+            },
+            ("continue" | "c", None) => {
+                loop {
+                    let (running, changed): (bool, bool) = self.step()?;
+                    if !running || changed || self.at_breakpoint() {
+                        break;
+                    }
+                }
+                self.stop()?;
+            },
+            ("break" | "b", Some(location)) => {
+                match parse_location(location) {
+                    Some((procedure, index)) => {
+                        self.breakpoints.insert((procedure.to_string(), index));
+                    },
+                    None => writeln!(self.output, "expected a breakpoint of the form procedure:index").map_err(RuntimeError::Io)?,
+                }
+            },
+            ("watch" | "w", Some(location)) => {
+                let Some((region, index)) = parse_location(location) else {
+                    writeln!(self.output, "expected a watch of the form region:index").map_err(RuntimeError::Io)?;
+                    return Ok(());
+                };
+                match self.cell(region, index) {
+                    Some(value) => {
+                        writeln!(self.output, "watching {}:{} ({:02x})", region, index, value).map_err(RuntimeError::Io)?;
+                        self.watches.push(Watch { region: region.to_string(), index, value });
+                    },
+                    None => writeln!(self.output, "no cell {} in region {}", index, region).map_err(RuntimeError::Io)?,
+                }
+            },
+            ("watch" | "w", None) => {
+                for watch in &self.watches {
+                    writeln!(self.output, "{}:{} ({:02x})", watch.region, watch.index, watch.value).map_err(RuntimeError::Io)?;
+                }
+            },
+            ("print" | "p", Some(name)) => {
+                match self.program.region_view(name) {
+                    Some(region) => print_region(self.output, &region).map_err(RuntimeError::Io)?,
+                    None => writeln!(self.output, "no region named {}", name).map_err(RuntimeError::Io)?,
+                }
+            },
+            ("stack", None) => {
+                for frame in self.program.call_stack().iter().rev() {
+                    writeln!(self.output, "{}", describe_frame(self.program, frame)).map_err(RuntimeError::Io)?;
+                }
+            },
+            ("regions", None) => {
+                for region in self.program.region_views() {
+                    writeln!(self.output, "{} ({} bytes, pointer at {})", region.name, region.bytes.len(), region.pointer).map_err(RuntimeError::Io)?;
+                }
+            },
+            _ => writeln!(self.output, "commands: step, continue, break <procedure:index>, watch [region:index], print <region>, stack, regions, quit").map_err(RuntimeError::Io)?,
+        }
+        return Ok(());
+    }
+}
+
+// A line based prompt wrapped around `Program::step`, stopping before the first instruction of `main`. Runtime errors
+// end the session, since the program can't be resumed after one anyway
+pub fn debug(program: &mut Program) -> Result<(), RuntimeError> {
+    return debug_with_io(program, &mut io::stdin().lock(), &mut io::stdout());
+}
+
+// The prompt and the program's own input and output all go through the given handles
+fn debug_with_io(program: &mut Program, input: &mut dyn BufRead, output: &mut dyn Write) -> Result<(), RuntimeError> {
+    let mut debugger: Debugger = Debugger {
+        program,
+        input,
+        output,
+        breakpoints: HashSet::new(),
+        watches: Vec::new(),
+    };
+    debugger.program.start();
+    debugger.stop()?;
+    loop {
+        write!(debugger.output, "(caedan) ").map_err(RuntimeError::Io)?;
+        debugger.output.flush().map_err(RuntimeError::Io)?;
+        let mut line: String = String::new();
+        if debugger.input.read_line(&mut line).map_err(RuntimeError::Io)? == 0 {
+            return Ok(());
+        }
+        let mut words = line.split_whitespace();
+        let Some(command) = words.next() else {
+            continue;
+        };
+        if (command == "quit") || (command == "q") {
+            return Ok(());
+        }
+        debugger.execute(command, words.next())?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn failing_assertions_end_a_watched_continue() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: + =02 ;").unwrap();
+        let mut output: Vec<u8> = Vec::new();
+        let result: Result<(), RuntimeError> = debug_with_io(&mut program, &mut &b"watch main:0\ncontinue\ncontinue\nstep\n"[..], &mut output);
+        assert!(matches!(result, Err(RuntimeError::AssertionFailed { expected: 2, actual: 1, .. })));
+        // The session stops at the error, without reading the step after it
+        assert_eq!(String::from_utf8(output).unwrap(), concat!(
+            "main:0 on main (Plus)\n",
+            "(caedan) watching main:0 (00)\n",
+            "(caedan) main:0 changed 00 -> 01 at main:0 on main (Plus)\n",
+            "main:1 on main (Assert(2))\n",
+            "(caedan) ",
+        ));
+    }
+}
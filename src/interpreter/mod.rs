@@ -1 +1,2 @@
-pub mod program;
\ No newline at end of file
+pub mod program;
+pub mod transpile;
@@ -1,17 +1,364 @@
-use std::{cell::RefCell, collections::{HashMap, VecDeque}, path::Path};
+use alloc::{boxed::Box, collections::{BTreeMap, BTreeSet, VecDeque}, format, string::{String, ToString}, sync::Arc, vec, vec::Vec};
+use core::{cell::{RefCell, RefMut}, num::NonZeroUsize, ops::Range, sync::atomic::AtomicBool, time::Duration};
+#[cfg(feature = "std")]
+use std::time::Instant;
+#[cfg(feature = "stdio")]
+use std::{fs::File, io::{self, BufReader}, path::Path};
 
-use crate::{parser::parser::{parse, ParseResult, ParseError}, procedure::Procedure, region::Region};
+#[cfg(feature = "mmap")]
+use memmap2::Mmap;
+
+#[cfg(feature = "stdio")]
+use crate::parser::parser::parse;
+use crate::{io::{Error, Read, Write}, parser::{char_stream::Span, json::{json_string, span_json}, parser::{is_valid_identifier, parse_reader, ParseOptions, ParseResult, ParseError}}, interpreter::transpile, procedure::{find_region, Instruction, Procedure, RegionReference}, region::{AccessStats, AccessTracking, PointerHistory, Region, RegionSnapshot, RegionTemplate, RegionView}};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ArithmeticMode {
+    #[default]
+    Wrapping,
+    Checked,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointerMode {
+    #[default]
+    Wrapping,
+    Strict,
+}
+
+// What `,` does once the input has run out
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EofMode {
+    #[default]
+    Unchanged,
+    Error,
+}
+
+// How `.` turns a cell into output. Cells are bytes, and every byte is also a valid Unicode scalar value (the Latin-1
+// range), so the codepoint encoding can't fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputEncoding {
+    #[default]
+    Raw,
+    Codepoint,
+}
+
+// When the write instructions flush the output. Only a buffered writer holds anything back to begin with, and with
+// `Full` it is left to flush on its own until the run is over
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputBuffering {
+    None,
+    // After any write that includes a newline
+    Line,
+    #[default]
+    Full,
+}
+
+// Every execution policy knob in one place. The defaults match a program built without any options
+#[derive(Debug, Clone, Default)]
+pub struct ProgramOptions {
+    pub parse: ParseOptions,
+    pub arithmetic_mode: ArithmeticMode,
+    pub pointer_mode: PointerMode,
+    pub eof_mode: EofMode,
+    pub aligned_transfers: bool,
+    pub output_encoding: OutputEncoding,
+    pub log_borrow_conflicts: bool,
+    // Write every byte a read instruction consumes to the output as well, for interactive sessions
+    pub echo_input: bool,
+    // Turns `=` into a no-op, for running self-checking programs without paying for the checks
+    pub ignore_assertions: bool,
+    // Fill regions with the poison sentinel so that reading a cell before writing it is an error
+    pub poison_regions: bool,
+    pub coverage: bool,
+    pub profile: bool,
+    pub access_tracking: AccessTracking,
+    pub pointer_history: PointerHistory,
+    // How many of the most recently executed instructions to keep around for `last_trace`, if any
+    pub trace_length: Option<NonZeroUsize>,
+}
+
+impl ProgramOptions {
+    // Fails loudly instead of wrapping or carrying on: moving past either end of a region, an increment or decrement
+    // that would wrap, and reading past the end of the input are all errors
+    pub fn strict() -> ProgramOptions {
+        return ProgramOptions {
+            arithmetic_mode: ArithmeticMode::Checked,
+            pointer_mode: PointerMode::Strict,
+            eof_mode: EofMode::Error,
+            ..ProgramOptions::default()
+        };
+    }
+}
+
+#[derive(Debug)]
+pub enum RuntimeError {
+    ArithmeticOverflow { region: String, pointer: usize },
+    PointerOutOfBounds { region: String, pointer: usize },
+    NoZeroCell(String),
+    UninitializedRead { region: String, pointer: usize },
+    UnexpectedEof { region: String, pointer: usize },
+    AssertionFailed { expected: u8, actual: u8, procedure: String, region: String, pointer: usize },
+    WriteToReadOnly { region: String, pointer: usize },
+    RegionConflict(String),
+    UnknownRegion(String),
+    UnknownProcedure(String),
+    MisalignedTransfer { region: String, pointer: usize, other: String },
+    InvalidWindow { region: String, window: Range<usize> },
+    OutputLimitExceeded(u64),
+    InstructionLimitExceeded(u64),
+    TimeLimitExceeded(Duration),
+    StackDepthExceeded(usize),
+    // Where the program was when the interrupt flag was seen: the instruction it was about to run, and the cell the
+    // head of its region was on
+    Interrupted { procedure: String, instruction: usize, region: String, pointer: usize },
+    Io(Error),
+}
+
+#[derive(Debug)]
+pub enum RenameError {
+    DuplicateIdentifier,
+    InvalidIdentifier,
+    MissingRegion,
+}
+
+// The immutable result of compiling a source file, which can be instantiated into any number of independent
+// programs without reparsing
+#[derive(Debug)]
+pub struct CompiledProgram {
+    regions: Arc<[RegionTemplate]>,
+    procedures: Arc<BTreeMap<String, Procedure>>,
+    procedure_order: Arc<[String]>,
+    aliases: Arc<BTreeMap<String, String>>,
+}
 
 #[derive(Debug)]
 pub struct Program {
-    regions: HashMap<String, RefCell<Region>>,
-    procedures: HashMap<String, Procedure>,
+    regions: BTreeMap<String, RefCell<Region>>,
+    // Declaration order, since the map on its own would enumerate regions differently from run to run
+    region_order: Vec<String>,
+    // What each region starts out as, in step with `region_order`, so that `reset` can put it back
+    templates: Arc<[RegionTemplate]>,
+    procedures: Arc<BTreeMap<String, Procedure>>,
+    // Declaration order, with anonymous procedures right before the procedure they were written in
+    procedure_order: Arc<[String]>,
+    // Instructions already name the regions themselves, so these only matter for lookups by name from outside
+    aliases: Arc<BTreeMap<String, String>>,
+    options: ProgramOptions,
+    call_stack: VecDeque<StackFrame>,
+    back_reference: String,
+    // Set along with the back reference whenever that is a transient region
+    back_transient: Option<usize>,
+    // The regions made for a single call, as in `proc@new[8]`, keyed by an id that is never reused within a run.
+    // They are kept out of `regions` so that nothing can reach them by name
+    transients: BTreeMap<usize, Transient>,
+    next_transient: usize,
+    metrics: RunMetrics,
+    // Per procedure, whether each instruction has run at least once
+    coverage: Option<BTreeMap<String, Vec<bool>>>,
+    // Instructions executed under each call stack, keyed by the stack in the folded format
+    profile: Option<BTreeMap<String, u64>>,
+    trace: Option<Trace>,
+    // When the current run has to stop by, if it has a time limit
+    #[cfg(feature = "std")]
+    deadline: Option<Instant>,
+}
+
+#[derive(Debug, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct RunMetrics {
+    pub instructions_executed: u64,
+    // Sends and receives that were dropped because the other region was in use
+    pub borrow_conflicts: u64,
+    pub bytes_written: u64,
+    pub max_stack_depth: usize,
+    pub procedures: BTreeMap<String, ProcStats>,
+    // Only filled in while access tracking is on, keyed by the regions' current names
+    pub regions: BTreeMap<String, AccessStats>,
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ProcStats {
+    pub calls: u64,
+    // Only the instructions run by the procedure itself, not those of the procedures it calls
+    pub instructions: u64,
 }
 
+impl RunMetrics {
+    // Procedures are sorted by name, so that the output of two runs can be diffed
+    pub fn to_json(&self) -> String {
+        let procedures: Vec<String> = self.procedures.iter().map(|(name, stats)| {
+            return format!("{}:{{\"calls\":{},\"instructions\":{}}}", json_string(name), stats.calls, stats.instructions);
+        }).collect();
+        let regions: Vec<String> = self.regions.iter().map(|(name, stats)| {
+            let histogram: String = match &stats.histogram {
+                Some(histogram) => format!(",\"histogram\":[{}]", histogram.iter().map(|count| count.to_string()).collect::<Vec<String>>().join(",")),
+                None => String::new(),
+            };
+            return format!("{}:{{\"reads\":{},\"writes\":{}{}}}", json_string(name), stats.reads, stats.writes, histogram);
+        }).collect();
+        return format!(
+            "{{\"instructions_executed\":{},\"borrow_conflicts\":{},\"bytes_written\":{},\"max_stack_depth\":{},\"procedures\":{{{}}},\"regions\":{{{}}}}}",
+            self.instructions_executed, self.borrow_conflicts, self.bytes_written, self.max_stack_depth, procedures.join(","), regions.join(","),
+        );
+    }
+
+    fn record_call(&mut self, procedure: &str, stack_depth: usize) -> () {
+        self.procedures.entry(procedure.to_string()).or_default().calls += 1;
+        self.max_stack_depth = self.max_stack_depth.max(stack_depth);
+    }
+}
+
+// Caps on how much a single run may do, where None means no limit
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RunLimits {
+    pub max_instructions: Option<u64>,
+    // In bytes written by any of the write instructions
+    pub max_output: Option<u64>,
+    // Only with `std`, which is where the clock comes from
+    #[cfg(feature = "std")]
+    pub time_limit: Option<Duration>,
+    pub max_stack_depth: Option<usize>,
+}
+
+// Everything about a single run, built up from `RunOptions::new()`. Without an input the program sees the end of
+// input straight away, and without an output whatever it writes is collected into the outcome instead
+pub struct RunOptions<'a> {
+    input: Box<dyn Read + 'a>,
+    output: Option<Box<dyn Write + 'a>>,
+    buffering: OutputBuffering,
+    limits: RunLimits,
+    interrupt: Option<&'a AtomicBool>,
+}
+
+impl<'a> Default for RunOptions<'a> {
+    fn default() -> RunOptions<'a> {
+        return RunOptions::new();
+    }
+}
+
+impl<'a> RunOptions<'a> {
+    pub fn new() -> RunOptions<'a> {
+        return RunOptions {
+            input: Box::new(&[][..]),
+            output: None,
+            buffering: OutputBuffering::default(),
+            limits: RunLimits::default(),
+            interrupt: None,
+        };
+    }
+
+    pub fn input<R: Read + 'a>(mut self, input: R) -> RunOptions<'a> {
+        self.input = Box::new(input);
+        return self;
+    }
+
+    // Streams the file through a buffer, failing only if it can't be opened
+    #[cfg(feature = "stdio")]
+    pub fn input_file(self, path: &Path) -> Result<RunOptions<'a>, io::Error> {
+        return Ok(self.input(BufReader::new(File::open(path)?)));
+    }
+
+    pub fn output<W: Write + 'a>(mut self, output: W) -> RunOptions<'a> {
+        self.output = Some(Box::new(output));
+        return self;
+    }
+
+    pub fn buffering(mut self, buffering: OutputBuffering) -> RunOptions<'a> {
+        self.buffering = buffering;
+        return self;
+    }
+
+    pub fn limits(mut self, limits: RunLimits) -> RunOptions<'a> {
+        self.limits = limits;
+        return self;
+    }
+
+    pub fn max_instructions(mut self, limit: u64) -> RunOptions<'a> {
+        self.limits.max_instructions = Some(limit);
+        return self;
+    }
+
+    pub fn max_output(mut self, limit: u64) -> RunOptions<'a> {
+        self.limits.max_output = Some(limit);
+        return self;
+    }
+
+    #[cfg(feature = "std")]
+    pub fn time_limit(mut self, limit: Duration) -> RunOptions<'a> {
+        self.limits.time_limit = Some(limit);
+        return self;
+    }
+
+    pub fn max_stack_depth(mut self, limit: usize) -> RunOptions<'a> {
+        self.limits.max_stack_depth = Some(limit);
+        return self;
+    }
+
+    // Polled before every instruction, so setting it from another thread or a signal handler stops the run with
+    // `RuntimeError::Interrupted` at the next one
+    pub fn interrupt(mut self, interrupt: &'a AtomicBool) -> RunOptions<'a> {
+        self.interrupt = Some(interrupt);
+        return self;
+    }
+}
+
+// Everything a test is likely to want to check after a run, all in one place
+#[derive(Debug)]
+pub struct RunOutcome {
+    pub output: Vec<u8>,
+    pub regions: BTreeMap<String, RegionSnapshot>,
+    pub instructions_executed: u64,
+}
+
+#[derive(Debug)]
 pub struct StackFrame {
     pub procedure: String,
+    // Only a name to show for a transient region, which is found by `transient` instead
     pub region: String,
     pub pointer: usize,
+    // The part of the region the frame is confined to, if it was called on a window
+    pub window: Option<Range<usize>>,
+    pub transient: Option<usize>,
+}
+
+// An instruction as it was about to run, along with the cell the head of its region was on at the time
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub procedure: String,
+    pub instruction: usize,
+    pub region: String,
+    pub pointer: usize,
+    pub cell: u8,
+}
+
+// A ring buffer of the last few instructions to run, so that a failed run can be looked back on without having traced
+// all of it
+#[derive(Debug)]
+pub struct Trace {
+    entries: VecDeque<TraceEntry>,
+    length: usize,
+}
+
+impl Trace {
+    pub fn new(length: NonZeroUsize) -> Trace {
+        return Trace { entries: VecDeque::with_capacity(length.get()), length: length.get() };
+    }
+
+    pub fn record(&mut self, entry: TraceEntry) -> () {
+        if self.entries.len() == self.length {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(entry);
+    }
+}
+
+// Freed as soon as no frame runs on it and it stops being the back reference
+#[derive(Debug)]
+struct Transient {
+    region: RefCell<Region>,
+    users: usize,
 }
 
 impl StackFrame {
@@ -20,56 +367,1154 @@ impl StackFrame {
             procedure: procedure.to_string(),
             region: region.to_string(),
             pointer,
+            window: None,
+            transient: None,
         };
     }
+
+    pub fn with_window(mut self, window: Option<Range<usize>>) -> StackFrame {
+        self.window = window;
+        return self;
+    }
+
+    pub fn with_transient(mut self, transient: Option<usize>) -> StackFrame {
+        self.transient = transient;
+        return self;
+    }
+}
+
+// Everything a procedure needs from the program beyond the region it's executing in
+pub struct ExecutionContext<'a> {
+    pub regions: &'a BTreeMap<String, RefCell<Region>>,
+    pub back_reference: &'a str,
+    // The region behind the back reference when it isn't in `regions`
+    pub back_transient: Option<&'a RefCell<Region>>,
+    pub arithmetic_mode: ArithmeticMode,
+    pub pointer_mode: PointerMode,
+    pub eof_mode: EofMode,
+    pub aligned_transfers: bool,
+    pub output_encoding: OutputEncoding,
+    pub output_buffering: OutputBuffering,
+    pub input: &'a mut dyn Read,
+    pub output: &'a mut dyn Write,
+    pub single_step: bool,
+    pub log_borrow_conflicts: bool,
+    pub echo_input: bool,
+    pub ignore_assertions: bool,
+    pub limits: RunLimits,
+    pub interrupt: Option<&'a AtomicBool>,
+    // Only set when there is a time limit, so that runs without one never need a clock
+    #[cfg(feature = "std")]
+    pub deadline: Option<Instant>,
+    pub metrics: &'a mut RunMetrics,
+    pub coverage: Option<&'a mut Vec<bool>>,
+    pub trace: Option<&'a mut Trace>,
 }
 
+// How a procedure stopped executing
+#[derive(Debug)]
+pub enum Exit {
+    Call(Call),
+    // Only when single stepping, with the pointer of the next instruction to run
+    Paused(usize),
+    Returned,
+}
+
+#[derive(Debug)]
 pub struct Call {
     pub procedure: String,
     pub region: String,
+    pub window: Option<Range<usize>>,
+    // The caller's current cell, for the first cell the callee can reach
+    pub argument: Option<u8>,
     pub return_pointer: Option<usize>,
+    // The size of the region to make for the call, in which case `region` is only the name it goes by
+    pub transient: Option<NonZeroUsize>,
 }
 
-impl Program {
-    pub fn from_source(source_path: &Path) -> Result<Program, ParseError> {
-        let result: ParseResult = parse(source_path)?;
-        let mut regions: HashMap<String, RefCell<Region>> = HashMap::new();
-        let mut procedures: HashMap<String, Procedure> = HashMap::new();
-        for region in result.regions.into_iter() {
-            regions.insert(region.name.clone(), RefCell::new(Region::new(&region.name, region.size)));
+impl CompiledProgram {
+    #[cfg(feature = "stdio")]
+    pub fn from_source(source_path: &Path) -> Result<CompiledProgram, ParseError> {
+        return Ok(CompiledProgram::from_parse_result(parse(source_path, &ParseOptions::default())?));
+    }
+
+    pub fn from_string(source: &str) -> Result<CompiledProgram, ParseError> {
+        return CompiledProgram::from_reader(source.as_bytes());
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<CompiledProgram, ParseError> {
+        return Ok(CompiledProgram::from_parse_result(parse_reader(reader, &ParseOptions::default())?));
+    }
+
+    pub fn from_parse_result(result: ParseResult) -> CompiledProgram {
+        let mut procedures: BTreeMap<String, Procedure> = BTreeMap::new();
+        let procedure_order: Arc<[String]> = result.procedures.iter().map(|procedure| procedure.name.clone()).collect();
+        for mut procedure in result.procedures.into_iter() {
+            procedure.resolve_aliases(&result.aliases);
+            procedures.insert(procedure.name.clone(), Procedure::new(&procedure.name, procedure.instructions, procedure.spans, procedure.is_anonymous));
         }
-        for procedure in result.procedures.into_iter() {
-            procedures.insert(procedure.name.clone(), Procedure::new(&procedure.name, procedure.instructions, procedure.is_anonymous));
+        let regions: Arc<[RegionTemplate]> = result.regions.iter().map(|region| {
+            let template: RegionTemplate = RegionTemplate::new(&region.name, region.size).with_readonly(region.readonly);
+            match &region.initial_bytes {
+                Some(bytes) => return template.with_initializer(bytes),
+                None => return template,
+            }
+        }).collect();
+        return CompiledProgram {
+            regions,
+            procedures: Arc::new(procedures),
+            procedure_order,
+            aliases: Arc::new(result.aliases),
+        };
+    }
+
+    pub fn instantiate(&self) -> Program {
+        let mut regions: BTreeMap<String, RefCell<Region>> = BTreeMap::new();
+        for region in self.regions.iter() {
+            regions.insert(region.name.clone(), RefCell::new(Region::from_template(region)));
         }
-        return Ok(Program { regions, procedures });
+        return Program {
+            regions,
+            region_order: self.regions.iter().map(|region| region.name.clone()).collect(),
+            templates: Arc::clone(&self.regions),
+            procedures: Arc::clone(&self.procedures),
+            procedure_order: Arc::clone(&self.procedure_order),
+            aliases: Arc::clone(&self.aliases),
+            options: ProgramOptions::default(),
+            call_stack: VecDeque::new(),
+            back_reference: "main".to_string(),
+            back_transient: None,
+            transients: BTreeMap::new(),
+            next_transient: 0,
+            metrics: RunMetrics::default(),
+            coverage: None,
+            profile: None,
+            trace: None,
+            #[cfg(feature = "std")]
+            deadline: None,
+        };
+    }
+
+    // Every input gets its own freshly instantiated program, so nothing carries over between runs
+    pub fn run_batch(&self, inputs: &[Vec<u8>]) -> Vec<Result<Vec<u8>, RuntimeError>> {
+        return inputs.iter().map(|input| self.instantiate().run_with_bytes(input)).collect();
+    }
+}
+
+impl Program {
+    #[cfg(feature = "stdio")]
+    pub fn from_source(source_path: &Path) -> Result<Program, ParseError> {
+        return Program::from_source_with_options(source_path, &ProgramOptions::default());
     }
 
-    // References are checked at compile time, so these will never fail
-    pub fn get_region(&self, name: &str) -> &RefCell<Region> {
-        return self.regions.get(name).unwrap();
+    pub fn from_string(source: &str) -> Result<Program, ParseError> {
+        return Program::from_string_with_options(source, &ProgramOptions::default());
     }
 
+    // For sources that aren't files or already in memory, such as sockets or decompressed streams
+    pub fn from_reader<R: Read>(reader: R) -> Result<Program, ParseError> {
+        return Program::from_reader_with_options(reader, &ProgramOptions::default());
+    }
+
+    #[cfg(feature = "stdio")]
+    pub fn from_source_with_options(source_path: &Path, options: &ProgramOptions) -> Result<Program, ParseError> {
+        let mut program: Program = Program::from_parse_result(parse(source_path, &options.parse)?);
+        program.set_options(options);
+        return Ok(program);
+    }
+
+    pub fn from_string_with_options(source: &str, options: &ProgramOptions) -> Result<Program, ParseError> {
+        return Program::from_reader_with_options(source.as_bytes(), options);
+    }
+
+    pub fn from_reader_with_options<R: Read>(reader: R, options: &ProgramOptions) -> Result<Program, ParseError> {
+        let mut program: Program = Program::from_parse_result(parse_reader(reader, &options.parse)?);
+        program.set_options(options);
+        return Ok(program);
+    }
+
+    pub fn from_parse_result(result: ParseResult) -> Program {
+        return CompiledProgram::from_parse_result(result).instantiate();
+    }
+
+    // References in the source are checked at compile time, but a name from the caller can still be unknown, or
+    // have been renamed away
+    pub fn get_region(&self, name: &str) -> Result<&RefCell<Region>, RuntimeError> {
+        return find_region(&self.regions, self.resolve_alias(name));
+    }
+
+    // Procedures can't be renamed, so any name taken from the program itself will be found
     pub fn get_procedure(&self, name: &str) -> &Procedure {
         return self.procedures.get(name).unwrap();
     }
 
-    pub fn run(self) -> () {
-        let mut call_stack: VecDeque<StackFrame> = VecDeque::new();
-        call_stack.push_back(StackFrame::new("main", "main", 0));
-        let mut back_reference: String = "main".to_string();
-        while !call_stack.is_empty() {
-            let frame: StackFrame = call_stack.pop_back().unwrap();
-            let procedure: &Procedure = self.get_procedure(&frame.procedure);
-            if !procedure.is_anonymous {
-                back_reference = frame.region.clone();
+    // Where in the source the instruction at the given index was written
+    pub fn span_at(&self, procedure: &str, index: usize) -> Option<Span> {
+        return self.procedures.get(procedure)?.span(index);
+    }
+
+    // Every instruction's span as JSON, in the format described under `--emit-sourcemap` in the README. Procedures
+    // come in declaration order so the file only changes when the program does
+    pub fn source_map(&self) -> String {
+        let procedures: Vec<String> = self.procedure_order.iter().map(|name| {
+            let procedure: &Procedure = &self.procedures[name];
+            let spans: Vec<String> = (0..procedure.len()).filter_map(|index| procedure.span(index)).map(|span| span_json(&span)).collect();
+            return format!("{}:[{}]", json_string(name), spans.join(","));
+        }).collect();
+        return format!("{{\"version\":1,\"procedures\":{{{}}}}}", procedures.join(","));
+    }
+
+    // Lowered instructions across every procedure, anonymous ones included
+    pub fn total_instructions(&self) -> usize {
+        return self.procedures.values().map(Procedure::len).sum();
+    }
+
+    // The procedures each procedure calls directly, anonymous ones included. Every procedure has an entry, even when
+    // it calls nothing
+    pub fn call_graph(&self) -> BTreeMap<String, BTreeSet<String>> {
+        return self.procedures.iter().map(|(name, procedure)| {
+            let callees: BTreeSet<String> = (0..procedure.len()).filter_map(|index| match procedure.instruction(index) {
+                Some(Instruction::Call(callee, _, _, _) | Instruction::CallTransient(callee, _, _)) => Some(callee.to_string()),
+                _ => None,
+            }).collect();
+            return (name.to_string(), callees);
+        }).collect();
+    }
+
+    // The call graph in Graphviz's DOT language, with anonymous procedures drawn dashed and each edge labelled with
+    // the region the call runs on, if it names one. Calls that only differ in where they are written share an edge
+    pub fn call_graph_dot(&self) -> String {
+        let mut source: String = String::from("digraph calls {\n");
+        for name in self.procedure_order.iter() {
+            let procedure: &Procedure = &self.procedures[name];
+            let style: &str = if procedure.is_anonymous { " [style=dashed]" } else { "" };
+            source.push_str(&format!("    {}{};\n", json_string(name), style));
+        }
+        for name in self.procedure_order.iter() {
+            let procedure: &Procedure = &self.procedures[name];
+            let mut edges: BTreeSet<(&str, String)> = BTreeSet::new();
+            for index in 0..procedure.len() {
+                match procedure.instruction(index) {
+                    Some(Instruction::Call(callee, region, window, _)) => {
+                        let mut label: String = match region {
+                            Some(RegionReference::Named(region)) => region.to_string(),
+                            Some(RegionReference::BackReference) => "$".to_string(),
+                            None => String::new(),
+                        };
+                        if let Some(window) = window {
+                            label.push_str(&format!("[{}:{}]", window.start, window.end));
+                        }
+                        edges.insert((callee, label));
+                    },
+                    Some(Instruction::CallTransient(callee, size, _)) => {
+                        edges.insert((callee, format!("new[{}]", size)));
+                    },
+                    _ => {},
+                }
             }
-            let region: &mut Region = &mut self.get_region(&frame.region).borrow_mut();
-            if let Some(call) = procedure.execute(region, frame.pointer, &self.regions, &back_reference) {
-                if let Some(pointer) = call.return_pointer {
-                    call_stack.push_back(StackFrame::new(&procedure.name, &region.name, pointer));
+            for (callee, label) in edges {
+                let label: String = if label.is_empty() { String::new() } else { format!(" [label={}]", json_string(&label)) };
+                source.push_str(&format!("    {} -> {}{};\n", json_string(name), json_string(callee), label));
+            }
+        }
+        source.push_str("}\n");
+        return source;
+    }
+
+    pub fn call_stack(&self) -> &VecDeque<StackFrame> {
+        return &self.call_stack;
+    }
+
+    // Frame pointers index into the lowered instructions, so this is what a frame is about to execute
+    pub fn instruction_at(&self, procedure: &str, index: usize) -> Option<&Instruction> {
+        return self.procedures.get(procedure)?.instruction(index);
+    }
+
+    pub fn region_names(&self) -> &[String] {
+        return &self.region_order;
+    }
+
+    pub fn procedure_names(&self) -> &[String] {
+        return &self.procedure_order;
+    }
+
+    // In declaration order, like everything else that lists regions
+    pub fn snapshots(&self) -> Vec<RegionSnapshot> {
+        return self.region_order.iter().map(|name| self.regions[name].borrow().snapshot()).collect();
+    }
+
+    // Region lookups by name accept aliases as well
+    fn resolve_alias<'a>(&'a self, name: &'a str) -> &'a str {
+        return self.aliases.get(name).map_or(name, String::as_str);
+    }
+
+    pub fn region(&self, name: &str) -> Option<RegionSnapshot> {
+        return Some(self.regions.get(self.resolve_alias(name))?.borrow().snapshot());
+    }
+
+    // No region is borrowed between steps, so exclusive access lets the view skip the RefCell entirely
+    pub fn region_view(&mut self, name: &str) -> Option<RegionView<'_>> {
+        let name: &str = self.aliases.get(name).map_or(name, String::as_str);
+        return Some(self.regions.get_mut(name)?.get_mut().view());
+    }
+
+    pub fn region_views(&mut self) -> Vec<RegionView<'_>> {
+        let positions: BTreeMap<&str, usize> = self.region_order.iter().enumerate().map(|(i, name)| (name.as_str(), i)).collect();
+        let mut views: Vec<RegionView> = self.regions.values_mut().map(|region| region.get_mut().view()).collect();
+        views.sort_by_key(|view| positions[view.name]);
+        return views;
+    }
+
+    // Send/receive and call lookups are keyed by name, so the map key and the region's own name have to move
+    // together. Aliases follow the region to its new name, while instructions still naming the old one will no
+    // longer find it
+    pub fn rename_region(&mut self, old: &str, new: &str) -> Result<(), RenameError> {
+        if !self.regions.contains_key(old) {
+            return Err(RenameError::MissingRegion);
+        }
+        if old == new {
+            return Ok(());
+        }
+        if !is_valid_identifier(new) {
+            return Err(RenameError::InvalidIdentifier);
+        }
+        // An alias by the new name would hide the region behind it
+        if self.regions.contains_key(new) || self.aliases.contains_key(new) {
+            return Err(RenameError::DuplicateIdentifier);
+        }
+        let region: RefCell<Region> = self.regions.remove(old).unwrap();
+        region.borrow_mut().name = new.to_string();
+        self.regions.insert(new.to_string(), region);
+        for name in self.region_order.iter_mut().filter(|name| *name == old) {
+            *name = new.to_string();
+        }
+        if self.aliases.values().any(|target| target == old) {
+            for target in Arc::make_mut(&mut self.aliases).values_mut().filter(|target| *target == old) {
+                *target = new.to_string();
+            }
+        }
+        return Ok(());
+    }
+
+    // Puts every region back the way it was before the first run, under its current name, and drops whatever run
+    // was in progress. Options are kept, so poisoned regions are poisoned again
+    pub fn reset(&mut self) -> () {
+        for (name, template) in self.region_order.iter().zip(self.templates.iter()) {
+            let mut region: Region = Region::from_template(template);
+            region.name = name.clone();
+            if self.options.poison_regions && !template.initialized {
+                region.poison();
+            }
+            region.set_access_tracking(self.options.access_tracking);
+            region.set_pointer_history(self.options.pointer_history);
+            self.regions.insert(name.clone(), RefCell::new(region));
+        }
+        self.call_stack.clear();
+        self.back_reference = "main".to_string();
+        self.back_transient = None;
+        self.transients.clear();
+        self.metrics = RunMetrics::default();
+        self.set_coverage(self.options.coverage);
+        self.set_profiling(self.options.profile);
+        self.set_trace(self.options.trace_length);
+    }
+
+    // Equivalent C source for the program as it stands, for when the interpreter is too slow. Only the default
+    // arithmetic and pointer modes carry over
+    pub fn to_c(&self) -> String {
+        return transpile::to_c(&self.regions, &self.region_order, &self.procedures, &self.procedure_order);
+    }
+
+    // Parse options are only kept for reference, since by now the program has already been parsed
+    pub fn set_options(&mut self, options: &ProgramOptions) -> () {
+        self.options = options.clone();
+        if options.poison_regions {
+            self.poison_regions();
+        }
+        self.set_coverage(options.coverage);
+        self.set_profiling(options.profile);
+        self.set_access_tracking(options.access_tracking);
+        self.set_pointer_history(options.pointer_history);
+        self.set_trace(options.trace_length);
+    }
+
+    pub fn options(&self) -> &ProgramOptions {
+        return &self.options;
+    }
+
+    pub fn set_arithmetic_mode(&mut self, mode: ArithmeticMode) -> () {
+        self.options.arithmetic_mode = mode;
+    }
+
+    // Refills every region with the poison sentinel, so this should happen before running. Regions declared with an
+    // initializer are left alone, since every one of their cells was given a value
+    pub fn poison_regions(&mut self) -> () {
+        self.options.poison_regions = true;
+        for (name, template) in self.region_order.iter().zip(self.templates.iter()) {
+            if !template.initialized {
+                self.regions[name].borrow_mut().poison();
+            }
+        }
+    }
+
+    pub fn set_pointer_mode(&mut self, mode: PointerMode) -> () {
+        self.options.pointer_mode = mode;
+    }
+
+    pub fn set_eof_mode(&mut self, mode: EofMode) -> () {
+        self.options.eof_mode = mode;
+    }
+
+    pub fn set_aligned_transfers(&mut self, enabled: bool) -> () {
+        self.options.aligned_transfers = enabled;
+    }
+
+    pub fn set_output_encoding(&mut self, encoding: OutputEncoding) -> () {
+        self.options.output_encoding = encoding;
+    }
+
+    pub fn set_coverage(&mut self, enabled: bool) -> () {
+        self.options.coverage = enabled;
+        if enabled {
+            self.coverage = Some(self.procedures.iter().map(|(name, procedure)| (name.clone(), vec![false; procedure.len()])).collect());
+        } else {
+            self.coverage = None;
+        }
+    }
+
+    pub fn coverage(&self) -> Option<&BTreeMap<String, Vec<bool>>> {
+        return self.coverage.as_ref();
+    }
+
+    pub fn set_profiling(&mut self, enabled: bool) -> () {
+        self.options.profile = enabled;
+        self.profile = if enabled { Some(BTreeMap::new()) } else { None };
+    }
+
+    pub fn set_trace(&mut self, length: Option<NonZeroUsize>) -> () {
+        self.options.trace_length = length;
+        self.trace = length.map(Trace::new);
+    }
+
+    // The instructions leading up to wherever the last run or step stopped, oldest first. After a runtime error, the
+    // last entry is the instruction that failed
+    pub fn last_trace(&self) -> Option<Vec<TraceEntry>> {
+        return self.trace.as_ref().map(|trace| trace.entries.iter().cloned().collect());
+    }
+
+    // One `main;helper;inner <count>` line per call stack, as consumed by inferno and flamegraph.pl. The counts are
+    // the exact number of instructions run with that stack rather than samples, and frames dropped by tail calls
+    // don't appear
+    pub fn folded_profile(&self) -> Option<String> {
+        let profile: &BTreeMap<String, u64> = self.profile.as_ref()?;
+        return Some(profile.iter().map(|(stack, count)| format!("{} {}\n", stack, count)).collect());
+    }
+
+    pub fn set_access_tracking(&mut self, tracking: AccessTracking) -> () {
+        self.options.access_tracking = tracking;
+        for region in self.regions.values() {
+            region.borrow_mut().set_access_tracking(tracking);
+        }
+    }
+
+    pub fn set_pointer_history(&mut self, history: PointerHistory) -> () {
+        self.options.pointer_history = history;
+        for region in self.regions.values() {
+            region.borrow_mut().set_pointer_history(history);
+        }
+    }
+
+    // Where the head of the region has been since the current run started, or None if nothing is being recorded
+    pub fn pointer_history(&self, name: &str) -> Option<Vec<usize>> {
+        return self.regions.get(self.resolve_alias(name))?.borrow().pointer_history();
+    }
+
+    // The counters live on the regions themselves, so they are copied into the metrics whenever a run or step ends
+    fn collect_access_stats(&mut self) -> () {
+        self.metrics.regions = self.regions.iter()
+            .filter_map(|(name, region)| Some((name.clone(), region.borrow().access_stats()?)))
+            .collect();
+    }
+
+    pub fn set_log_borrow_conflicts(&mut self, enabled: bool) -> () {
+        self.options.log_borrow_conflicts = enabled;
+    }
+
+    pub fn set_echo_input(&mut self, enabled: bool) -> () {
+        self.options.echo_input = enabled;
+    }
+
+    #[cfg(feature = "stdio")]
+    pub fn run(&mut self) -> Result<(), RuntimeError> {
+        return self.run_with_io(io::stdin(), io::stdout());
+    }
+
+    // Input comes from the file a buffer at a time, so even inputs far larger than memory can be streamed through.
+    // Running out of input behaves exactly as it does on stdin
+    #[cfg(feature = "stdio")]
+    pub fn run_with_file(&mut self, path: &Path) -> Result<(), RuntimeError> {
+        self.run_with_options(RunOptions::new().input_file(path).map_err(RuntimeError::Io)?.output(io::stdout()))?;
+        return Ok(());
+    }
+
+    // The read instructions take their bytes straight out of the mapping, leaving the OS to page the file in as it
+    // goes. The end of the file is the end of input, just as with `run_with_file`
+    #[cfg(feature = "mmap")]
+    pub fn run_with_mmap(&mut self, path: &Path) -> Result<(), RuntimeError> {
+        let file: File = File::open(path).map_err(RuntimeError::Io)?;
+        // The mapping is only sound as long as nothing truncates the file during the run, which is on the caller
+        let map: Mmap = unsafe { Mmap::map(&file) }.map_err(RuntimeError::Io)?;
+        return self.run_with_io(&map[..], io::stdout());
+    }
+
+    pub fn run_with_bytes(&mut self, input: &[u8]) -> Result<Vec<u8>, RuntimeError> {
+        return Ok(self.run_scripted(input)?.output);
+    }
+
+    pub fn run_scripted(&mut self, input: &[u8]) -> Result<RunOutcome, RuntimeError> {
+        return self.run_with_options(RunOptions::new().input(input));
+    }
+
+    pub fn run_with_io<R: Read, W: Write>(&mut self, input: R, output: W) -> Result<(), RuntimeError> {
+        self.run_with_options(RunOptions::new().input(input).output(output))?;
+        return Ok(());
+    }
+
+    // The one entry point every other way of running a program goes through
+    pub fn run_with_options(&mut self, options: RunOptions) -> Result<RunOutcome, RuntimeError> {
+        let RunOptions { mut input, output, buffering, limits, interrupt } = options;
+        let mut captured: Vec<u8> = Vec::new();
+        let mut output: Box<dyn Write + '_> = output.unwrap_or_else(|| Box::new(&mut captured));
+        self.start();
+        #[cfg(feature = "std")]
+        {
+            self.deadline = limits.time_limit.map(|limit| Instant::now() + limit);
+        }
+        while self.is_running() {
+            if let Err(error) = self.advance(&mut *input, &mut *output, buffering, false, &limits, interrupt) {
+                self.collect_access_stats();
+                return Err(error);
+            }
+        }
+        self.collect_access_stats();
+        output.flush().map_err(RuntimeError::Io)?;
+        drop(output);
+        return Ok(RunOutcome {
+            output: captured,
+            regions: self.regions.iter().map(|(name, region)| (name.clone(), region.borrow().snapshot())).collect(),
+            instructions_executed: self.metrics.instructions_executed,
+        });
+    }
+
+    // Runs each procedure to completion on the given region, one after the other. Regions keep whatever the previous
+    // stage left in them, read/write heads included, and the metrics cover the whole pipeline
+    #[cfg(feature = "stdio")]
+    pub fn run_pipeline(&mut self, procedures: &[&str], region: &str) -> Result<(), RuntimeError> {
+        return self.run_pipeline_with_io(procedures, region, io::stdin(), io::stdout());
+    }
+
+    pub fn run_pipeline_with_io<R: Read, W: Write>(&mut self, procedures: &[&str], region: &str, mut input: R, mut output: W) -> Result<(), RuntimeError> {
+        let aliases: Arc<BTreeMap<String, String>> = Arc::clone(&self.aliases);
+        let region: &str = aliases.get(region).map_or(region, String::as_str);
+        find_region(&self.regions, region)?;
+        if let Some(missing) = procedures.iter().find(|procedure| !self.procedures.contains_key(**procedure)) {
+            return Err(RuntimeError::UnknownProcedure(missing.to_string()));
+        }
+        self.reset_run_state();
+        for procedure in procedures {
+            self.enter(procedure, region);
+            while self.is_running() {
+                if let Err(error) = self.advance(&mut input, &mut output, OutputBuffering::default(), false, &RunLimits::default(), None) {
+                    self.collect_access_stats();
+                    return Err(error);
                 }
-                call_stack.push_back(StackFrame::new(&call.procedure, &call.region, 0));
             }
         }
+        self.collect_access_stats();
+        output.flush().map_err(RuntimeError::Io)?;
+        return Ok(());
+    }
+
+    // Sets up a fresh call stack at the start of `main`, ready to be stepped through
+    pub fn start(&mut self) -> () {
+        self.reset_run_state();
+        self.enter("main", "main");
+    }
+
+    // Everything a new run starts over with, short of entering a procedure, which pipelines do once per stage
+    fn reset_run_state(&mut self) -> () {
+        self.metrics = RunMetrics::default();
+        #[cfg(feature = "std")]
+        {
+            self.deadline = None;
+        }
+        if self.coverage.is_some() {
+            self.set_coverage(true);
+        }
+        if self.profile.is_some() {
+            self.set_profiling(true);
+        }
+        if self.options.access_tracking != AccessTracking::Off {
+            self.set_access_tracking(self.options.access_tracking);
+        }
+        if self.options.pointer_history != PointerHistory::Off {
+            self.set_pointer_history(self.options.pointer_history);
+        }
+        if self.trace.is_some() {
+            self.set_trace(self.options.trace_length);
+        }
+    }
+
+    fn enter(&mut self, procedure: &str, region: &str) -> () {
+        self.call_stack.clear();
+        self.call_stack.push_back(StackFrame::new(procedure, region, 0));
+        self.back_reference = region.to_string();
+        self.back_transient = None;
+        self.transients.clear();
+        self.metrics.record_call(procedure, 1);
+    }
+
+    pub fn instructions_executed(&self) -> u64 {
+        return self.metrics.instructions_executed;
+    }
+
+    pub fn metrics(&self) -> &RunMetrics {
+        return &self.metrics;
+    }
+
+    pub fn is_running(&self) -> bool {
+        return !self.call_stack.is_empty();
+    }
+
+    // Executes a single instruction, returning whether the program is still running afterwards
+    #[cfg(feature = "stdio")]
+    pub fn step(&mut self) -> Result<bool, RuntimeError> {
+        return self.step_with_io(&mut io::stdin(), &mut io::stdout());
+    }
+
+    pub fn step_with_io(&mut self, input: &mut dyn Read, output: &mut dyn Write) -> Result<bool, RuntimeError> {
+        if self.is_running() {
+            let result: Result<(), RuntimeError> = self.advance(input, output, OutputBuffering::default(), true, &RunLimits::default(), None);
+            self.collect_access_stats();
+            result?;
+            output.flush().map_err(RuntimeError::Io)?;
+        }
+        return Ok(self.is_running());
+    }
+
+    fn advance(&mut self, input: &mut dyn Read, output: &mut dyn Write, buffering: OutputBuffering, single_step: bool, limits: &RunLimits, interrupt: Option<&AtomicBool>) -> Result<(), RuntimeError> {
+        let frame: StackFrame = self.call_stack.pop_back().unwrap();
+        let procedure: &Procedure = self.procedures.get(&frame.procedure).unwrap();
+        if !procedure.is_anonymous {
+            self.back_reference = frame.region.clone();
+            retain(&mut self.transients, frame.transient);
+            let previous: Option<usize> = core::mem::replace(&mut self.back_transient, frame.transient);
+            release(&mut self.transients, previous);
+        }
+        let mut guard: RefMut<Region> = match frame.transient {
+            Some(id) => self.transients[&id].region.borrow_mut(),
+            None => find_region(&self.regions, &frame.region)?.borrow_mut(),
+        };
+        let region: &mut Region = &mut guard;
+        // Windows belong to frames rather than regions, so every frame puts its own in place, or clears the last one
+        if region.set_window(frame.window.clone()).is_none() {
+            return Err(RuntimeError::InvalidWindow { region: region.name.to_string(), window: frame.window.unwrap() });
+        }
+        let executed: u64 = self.metrics.instructions_executed;
+        let mut context: ExecutionContext = ExecutionContext {
+            regions: &self.regions,
+            back_reference: &self.back_reference,
+            back_transient: self.back_transient.map(|id| &self.transients[&id].region),
+            arithmetic_mode: self.options.arithmetic_mode,
+            pointer_mode: self.options.pointer_mode,
+            eof_mode: self.options.eof_mode,
+            aligned_transfers: self.options.aligned_transfers,
+            output_encoding: self.options.output_encoding,
+            output_buffering: buffering,
+            input,
+            output,
+            single_step,
+            log_borrow_conflicts: self.options.log_borrow_conflicts,
+            echo_input: self.options.echo_input,
+            ignore_assertions: self.options.ignore_assertions,
+            limits: *limits,
+            interrupt,
+            #[cfg(feature = "std")]
+            deadline: self.deadline,
+            metrics: &mut self.metrics,
+            coverage: self.coverage.as_mut().and_then(|coverage| coverage.get_mut(&frame.procedure)),
+            trace: self.trace.as_mut(),
+        };
+        let exit: Result<Exit, RuntimeError> = procedure.execute(region, frame.pointer, &mut context);
+        region.set_window(None);
+        // Every frame's procedure was counted as called when the frame was pushed, so it always has an entry
+        if let Some(stats) = self.metrics.procedures.get_mut(&frame.procedure) {
+            stats.instructions += self.metrics.instructions_executed - executed;
+        }
+        // The frames still on the stack are exactly the callers waiting on this one
+        if let Some(profile) = &mut self.profile {
+            let mut stack: Vec<&str> = self.call_stack.iter().map(|caller| caller.procedure.as_str()).collect();
+            stack.push(&frame.procedure);
+            *profile.entry(stack.join(";")).or_default() += self.metrics.instructions_executed - executed;
+        }
+        let exit: Exit = exit?;
+        // The argument goes in while the caller's region is still borrowed, and before the callee's window is
+        // checked, which only happens once its frame starts
+        let mut created: Option<Region> = None;
+        let mut target: Option<usize> = None;
+        if let Exit::Call(call) = &exit {
+            // A transient region can only be reached by staying on it or through `$`, and its name can't clash with
+            // a declared one
+            if let Some(size) = call.transient {
+                let mut transient: Region = Region::new(&call.region, size);
+                if self.options.poison_regions {
+                    transient.poison();
+                }
+                created = Some(transient);
+            } else if call.region == region.name {
+                target = frame.transient;
+            } else if call.region == self.back_reference {
+                target = self.back_transient;
+            }
+            if let Some(value) = call.argument {
+                let mut other: Option<RefMut<Region>> = None;
+                if created.is_none() && (call.region != region.name) {
+                    other = Some(match target {
+                        Some(id) => self.transients[&id].region.borrow_mut(),
+                        None => find_region(&self.regions, &call.region)?.borrow_mut(),
+                    });
+                }
+                let callee: &mut Region = match created.as_mut() {
+                    Some(transient) => transient,
+                    None => other.as_deref_mut().unwrap_or(region),
+                };
+                let first: usize = call.window.as_ref().map_or(0, |window| window.start);
+                if callee.is_readonly() {
+                    return Err(RuntimeError::WriteToReadOnly { region: callee.name.to_string(), pointer: first });
+                }
+                if call.window.as_ref().is_some_and(|window| window.is_empty() || (window.end > callee.len())) {
+                    return Err(RuntimeError::InvalidWindow { region: callee.name.to_string(), window: call.window.clone().unwrap() });
+                }
+                callee.set_cell(first, value);
+            }
+        }
+        drop(guard);
+        match exit {
+            Exit::Call(call) => {
+                if let Some(pointer) = call.return_pointer {
+                    self.push_frame(StackFrame::new(&frame.procedure, &frame.region, pointer).with_window(frame.window).with_transient(frame.transient));
+                }
+                if let Some(transient) = created {
+                    target = Some(self.next_transient);
+                    self.transients.insert(self.next_transient, Transient { region: RefCell::new(transient), users: 0 });
+                    self.next_transient += 1;
+                }
+                self.push_frame(StackFrame::new(&call.procedure, &call.region, 0).with_window(call.window).with_transient(target));
+                self.metrics.record_call(&call.procedure, self.call_stack.len());
+                if limits.max_stack_depth.is_some_and(|limit| self.call_stack.len() > limit) {
+                    return Err(RuntimeError::StackDepthExceeded(limits.max_stack_depth.unwrap()));
+                }
+            },
+            Exit::Paused(pointer) => {
+                self.push_frame(StackFrame::new(&frame.procedure, &frame.region, pointer).with_window(frame.window).with_transient(frame.transient));
+            },
+            Exit::Returned => {},
+        }
+        release(&mut self.transients, frame.transient);
+        // Nothing is left to reach the back reference through, so a transient region it still holds isn't needed
+        if self.call_stack.is_empty() {
+            let previous: Option<usize> = self.back_transient.take();
+            release(&mut self.transients, previous);
+        }
+        return Ok(());
+    }
+
+    fn push_frame(&mut self, frame: StackFrame) -> () {
+        retain(&mut self.transients, frame.transient);
+        self.call_stack.push_back(frame);
+    }
+}
+
+// Every frame on a transient region and the back reference each count as a user, so a region made for a call goes
+// away with the last frame that ran on it, tail calls included
+fn retain(transients: &mut BTreeMap<usize, Transient>, transient: Option<usize>) -> () {
+    if let Some(transient) = transient.and_then(|id| transients.get_mut(&id)) {
+        transient.users += 1;
+    }
+}
+
+fn release(transients: &mut BTreeMap<usize, Transient>, transient: Option<usize>) -> () {
+    let Some(id) = transient else {
+        return;
+    };
+    if let Some(entry) = transients.get_mut(&id) {
+        entry.users -= 1;
+        if entry.users == 0 {
+            transients.remove(&id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn strict_poisoned() -> ProgramOptions {
+        return ProgramOptions { poison_regions: true, ..ProgramOptions::strict() };
+    }
+
+    #[test]
+    fn rotated_cells_stay_initialized_under_strict_mode() {
+        let mut program: Program = Program::from_string_with_options("region main[2]; proc main: \"01 *rotate 1 > . ;", &strict_poisoned()).unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), vec![1]);
+        let mut program: Program = Program::from_string_with_options("region main[2]; proc main: \"01 *rotate 1 . ;", &strict_poisoned()).unwrap();
+        assert!(matches!(program.run_with_bytes(&[]), Err(RuntimeError::UninitializedRead { pointer: 0, .. })));
+    }
+
+    #[test]
+    fn pipeline_metrics_only_count_its_stages() {
+        let mut program: Program = Program::from_string("region main[2]; proc main: ; proc double: [->++<]> ; proc add: + ;").unwrap();
+        program.run_pipeline_with_io(&["add", "double", "add"], "main", &[][..], Vec::new()).unwrap();
+        let metrics: &RunMetrics = program.metrics();
+        assert_eq!(metrics.procedures.keys().collect::<Vec<&String>>(), ["add", "double"]);
+        assert_eq!(metrics.procedures["add"].calls, 2);
+        assert_eq!(metrics.procedures["double"].calls, 1);
+        assert_eq!(metrics.instructions_executed, 1 + 8 + 1);
+    }
+
+    #[test]
+    fn renaming_a_region_moves_its_aliases_along() {
+        let mut program: Program = Program::from_string("region main[1]; region data[2]; alias input = data; proc main: ;").unwrap();
+        program.rename_region("data", "buffer").unwrap();
+        assert!(program.region("data").is_none());
+        assert_eq!(program.region("input").unwrap().name, "buffer");
+        assert_eq!(program.region_names(), ["main", "buffer"]);
+        assert!(matches!(program.get_region("data"), Err(RuntimeError::UnknownRegion(name)) if name == "data"));
+        assert_eq!(program.get_region("input").unwrap().borrow().name, "buffer");
+    }
+
+    #[test]
+    fn renaming_a_region_checks_the_new_name() {
+        let mut program: Program = Program::from_string("region main[1]; region data[2]; alias input = data; proc main: ;").unwrap();
+        assert!(program.rename_region("data", "data").is_ok());
+        assert!(matches!(program.rename_region("data", "main"), Err(RenameError::DuplicateIdentifier)));
+        assert!(matches!(program.rename_region("data", "input"), Err(RenameError::DuplicateIdentifier)));
+        assert!(matches!(program.rename_region("data", "not valid"), Err(RenameError::InvalidIdentifier)));
+        assert!(matches!(program.rename_region("data", "proc"), Err(RenameError::InvalidIdentifier)));
+        assert!(matches!(program.rename_region("data", ""), Err(RenameError::InvalidIdentifier)));
+        assert!(matches!(program.rename_region("missing", "missing"), Err(RenameError::MissingRegion)));
+    }
+
+    #[test]
+    fn metrics_json_lists_procedures_by_name() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: zeta alpha ; proc zeta: + ; proc alpha: - ;").unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert_eq!(
+            program.metrics().to_json(),
+            "{\"instructions_executed\":4,\"borrow_conflicts\":0,\"bytes_written\":0,\"max_stack_depth\":2,\"procedures\":{\"alpha\":{\"calls\":1,\"instructions\":1},\"main\":{\"calls\":1,\"instructions\":2},\"zeta\":{\"calls\":1,\"instructions\":1}},\"regions\":{}}",
+        );
+    }
+
+    #[test]
+    fn recursive_calls_share_the_head() {
+        let mut program: Program = Program::from_string("region t[6]; proc main: \"03^t rec@t ; proc rec: [-*copy 1>rec] ;").unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        let region: RegionSnapshot = program.region("t").unwrap();
+        assert_eq!(region.bytes, [2, 1, 0, 0, 0, 0]);
+        assert_eq!(region.pointer, 3);
+        // The call is the last instruction of `main`, so only the levels waiting on one another are on the stack
+        assert_eq!(program.metrics().max_stack_depth, 4);
+        assert_eq!(program.metrics().procedures["rec"].calls, 4);
+    }
+
+    #[test]
+    fn recursive_calls_can_move_back_to_their_own_cell() {
+        let mut program: Program = Program::from_string("region t[6]; proc main: \"03^t rec@t ; proc rec: [-*copy 1>rec<] ;").unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        let region: RegionSnapshot = program.region("t").unwrap();
+        assert_eq!(region.pointer, 0);
+        assert_eq!(region.bytes, [0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn instruction_limit_allows_exactly_the_limit() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: +++ ;").unwrap();
+        assert!(program.run_with_options(RunOptions::new().max_instructions(3)).is_ok());
+        let mut program: Program = Program::from_string("region main[1]; proc main: +++ ;").unwrap();
+        assert!(matches!(program.run_with_options(RunOptions::new().max_instructions(2)), Err(RuntimeError::InstructionLimitExceeded(2))));
+        let mut program: Program = Program::from_string("region main[1]; proc main: +[>] ;").unwrap();
+        assert!(matches!(program.run_with_options(RunOptions::new().max_instructions(1000)), Err(RuntimeError::InstructionLimitExceeded(1000))));
+    }
+
+    #[test]
+    fn output_limit_keeps_what_fits() {
+        let mut written: Vec<u8> = Vec::new();
+        let mut program: Program = Program::from_string("region main[1]; proc main: +[.] ;").unwrap();
+        let result: Result<RunOutcome, RuntimeError> = program.run_with_options(RunOptions::new().output(&mut written).max_output(3));
+        assert!(matches!(result, Err(RuntimeError::OutputLimitExceeded(3))));
+        assert_eq!(written, [1, 1, 1]);
+        let mut program: Program = Program::from_string("region main[1]; proc main: +.+.+. ;").unwrap();
+        assert_eq!(program.run_with_options(RunOptions::new().max_output(3)).unwrap().output, [1, 2, 3]);
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn time_limit_stops_an_endless_loop() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: +[>] ;").unwrap();
+        let result: Result<RunOutcome, RuntimeError> = program.run_with_options(RunOptions::new().time_limit(Duration::from_millis(20)));
+        assert!(matches!(result, Err(RuntimeError::TimeLimitExceeded(limit)) if limit == Duration::from_millis(20)));
+    }
+
+    #[test]
+    fn stack_depth_limit_stops_runaway_recursion() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: f ; proc f: f + ;").unwrap();
+        assert!(matches!(program.run_with_options(RunOptions::new().max_stack_depth(8)), Err(RuntimeError::StackDepthExceeded(8))));
+        let mut program: Program = Program::from_string("region main[1]; proc main: f + ; proc f: g + ; proc g: + ;").unwrap();
+        assert_eq!(program.run_with_options(RunOptions::new().max_stack_depth(3)).unwrap().regions["main"].bytes, [3]);
+        let mut program: Program = Program::from_string("region main[1]; proc main: f + ; proc f: g + ; proc g: + ;").unwrap();
+        assert!(matches!(program.run_with_options(RunOptions::new().max_stack_depth(2)), Err(RuntimeError::StackDepthExceeded(2))));
+    }
+
+    #[test]
+    fn reset_restores_regions_between_runs() {
+        let source: &str = "region main[1]; region table[2] = {5, 6}; proc main: + . bump@table ; proc bump: + . ;";
+        let mut program: Program = Program::from_string(source).unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [1, 6]);
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [2, 7]);
+        program.reset();
+        assert_eq!(program.region("table").unwrap().bytes, [5, 6]);
+        assert_eq!(program.region("main").unwrap().bytes, [0]);
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [1, 6]);
+    }
+
+    #[test]
+    fn reset_keeps_names_and_options() {
+        let mut program: Program = Program::from_string_with_options("region main[1]; region data[2]; proc main: ;", &strict_poisoned()).unwrap();
+        program.rename_region("data", "renamed").unwrap();
+        program.reset();
+        assert!(program.region("data").is_none());
+        assert_eq!(program.region("renamed").unwrap().bytes, [0xaa, 0xaa]);
+        assert_eq!(program.options().pointer_mode, PointerMode::Strict);
+    }
+
+    #[test]
+    fn reset_drops_a_run_in_progress() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: +++ . ;").unwrap();
+        program.start();
+        let mut input: &[u8] = &[];
+        let mut output: Vec<u8> = Vec::new();
+        assert!(program.step_with_io(&mut input, &mut output).unwrap());
+        program.reset();
+        assert!(!program.is_running());
+        assert_eq!(program.metrics().instructions_executed, 0);
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [3]);
+    }
+
+    #[test]
+    fn batch_runs_start_from_a_fresh_program() {
+        let compiled: CompiledProgram = CompiledProgram::from_string("region main[1]; region count[1] = {10}; proc main: , . tally@count ; proc tally: + . ;").unwrap();
+        let results: Vec<Result<Vec<u8>, RuntimeError>> = compiled.run_batch(&[b"a".to_vec(), b"b".to_vec(), Vec::new()]);
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[0].as_ref().unwrap(), &[b'a', 11]);
+        assert_eq!(results[1].as_ref().unwrap(), &[b'b', 11]);
+        assert_eq!(results[2].as_ref().unwrap(), &[0, 11]);
+    }
+
+    #[test]
+    fn batch_keeps_going_past_a_failed_run() {
+        let compiled: CompiledProgram = CompiledProgram::from_string("region main[1]; proc main: , =41 . ;").unwrap();
+        let results: Vec<Result<Vec<u8>, RuntimeError>> = compiled.run_batch(&[b"A".to_vec(), b"B".to_vec(), b"A".to_vec()]);
+        assert_eq!(results[0].as_ref().unwrap(), b"A");
+        assert!(matches!(results[1], Err(RuntimeError::AssertionFailed { expected: 0x41, actual: 0x42, .. })));
+        assert_eq!(results[2].as_ref().unwrap(), b"A");
+    }
+
+    #[cfg(feature = "mmap")]
+    #[test]
+    fn mapped_input_runs_out_like_a_streamed_file() {
+        let directory: std::path::PathBuf = std::env::temp_dir().join(format!("caedan-mmap-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        for (name, contents) in [("short", &b"abc"[..]), ("empty", &b""[..])] {
+            let path: std::path::PathBuf = directory.join(name);
+            std::fs::write(&path, contents).unwrap();
+            let source: &str = "region main[5]; proc main: \"07 , > \"07 , > \"07 , > \"07 , > \"07 , ;";
+            let mut streamed: Program = Program::from_string(source).unwrap();
+            let mut mapped: Program = Program::from_string(source).unwrap();
+            assert!(streamed.run_with_file(&path).is_ok());
+            assert!(mapped.run_with_mmap(&path).is_ok());
+            assert_eq!(mapped.region("main").unwrap().bytes, streamed.region("main").unwrap().bytes);
+            let mut streamed: Program = Program::from_string_with_options(source, &ProgramOptions::strict()).unwrap();
+            let mut mapped: Program = Program::from_string_with_options(source, &ProgramOptions::strict()).unwrap();
+            let streamed_pointer: usize = match streamed.run_with_file(&path) {
+                Err(RuntimeError::UnexpectedEof { pointer, .. }) => pointer,
+                other => panic!("expected the end of input, got {:?}", other),
+            };
+            assert!(matches!(mapped.run_with_mmap(&path), Err(RuntimeError::UnexpectedEof { pointer, .. }) if pointer == streamed_pointer));
+        }
+        let mut program: Program = Program::from_string("region main[1]; proc main: , ;").unwrap();
+        assert!(matches!(program.run_with_mmap(&directory.join("missing")), Err(RuntimeError::Io(_))));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn every_transient_call_gets_a_fresh_region() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: f@new[2] f@new[2] ; proc f: + . > + . ;").unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [1, 1, 1, 1]);
+        assert_eq!(program.region("main").unwrap().bytes, [0]);
+        assert!(program.transients.is_empty());
+    }
+
+    #[test]
+    fn recursion_levels_get_a_transient_region_each() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: \"02 r!@new[1] ; proc r: . [- r!@new[1] .] ;").unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [2, 1, 0, 0, 1, 0, 0]);
+        assert!(program.transients.is_empty());
+    }
+
+    #[test]
+    fn bang_hands_the_current_cell_to_the_transient_region() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: \"05 f!@new[2] . ; proc f: . > . + ;").unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [5, 0, 5]);
+    }
+
+    #[test]
+    fn transient_region_is_shared_with_calls_made_on_it() {
+        let source: &str = "region main[1]; region other[1]; proc main: f@new[1] (.)@other ; proc f: \"2a g (&$)@other ; proc g: + . ;";
+        let mut program: Program = Program::from_string(source).unwrap();
+        assert_eq!(program.run_with_bytes(&[]).unwrap(), [0x2b, 0x2b]);
+        assert!(program.transients.is_empty());
+    }
+
+    #[test]
+    fn trace_keeps_only_the_last_few_instructions() {
+        let options: ProgramOptions = ProgramOptions { trace_length: NonZeroUsize::new(3), ..ProgramOptions::strict() };
+        let mut program: Program = Program::from_string_with_options("region main[2]; proc main: + + > + > ;", &options).unwrap();
+        assert!(matches!(program.run_with_bytes(&[]), Err(RuntimeError::PointerOutOfBounds { .. })));
+        let entry = |instruction: usize, pointer: usize, cell: u8| -> TraceEntry {
+            return TraceEntry { procedure: "main".to_string(), instruction, region: "main".to_string(), pointer, cell };
+        };
+        assert_eq!(program.last_trace().unwrap(), [entry(2, 0, 2), entry(3, 1, 0), entry(4, 1, 1)]);
+    }
+
+    #[test]
+    fn pointer_history_keeps_only_the_most_recent_positions() {
+        let source: &str = "region main[4]; proc main: > > > < < + ;";
+        let options: ProgramOptions = ProgramOptions { pointer_history: PointerHistory::All, ..ProgramOptions::default() };
+        let mut program: Program = Program::from_string_with_options(source, &options).unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert_eq!(program.pointer_history("main").unwrap(), [0, 1, 2, 3, 2, 1]);
+        let options: ProgramOptions = ProgramOptions { pointer_history: PointerHistory::Last(NonZeroUsize::new(3).unwrap()), ..ProgramOptions::default() };
+        let mut program: Program = Program::from_string_with_options(source, &options).unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert_eq!(program.pointer_history("main").unwrap(), [3, 2, 1]);
+        let mut program: Program = Program::from_string(source).unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert!(program.pointer_history("main").is_none());
+    }
+
+    // Remembers how much had been written each time it was flushed
+    struct FlushLog<'a> {
+        written: &'a mut Vec<u8>,
+        flushes: &'a mut Vec<usize>,
+    }
+
+    impl Write for FlushLog<'_> {
+        fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+            self.written.extend_from_slice(bytes);
+            return Ok(());
+        }
+
+        fn flush(&mut self) -> Result<(), Error> {
+            self.flushes.push(self.written.len());
+            return Ok(());
+        }
+    }
+
+    fn flushes(buffering: OutputBuffering) -> Vec<usize> {
+        let mut written: Vec<u8> = Vec::new();
+        let mut flushes: Vec<usize> = Vec::new();
+        let mut program: Program = Program::from_string("region main[1]; proc main: \"41 . \"0a . \"42 . \"43 . \"0a . \"44 . ;").unwrap();
+        let log: FlushLog = FlushLog { written: &mut written, flushes: &mut flushes };
+        program.run_with_options(RunOptions::new().output(log).buffering(buffering)).unwrap();
+        assert_eq!(written, b"A\nBC\nD");
+        return flushes;
+    }
+
+    #[test]
+    fn line_buffering_flushes_after_each_newline() {
+        assert_eq!(flushes(OutputBuffering::Line), [2, 5, 6]);
+        assert_eq!(flushes(OutputBuffering::None), [1, 2, 3, 4, 5, 6, 6]);
+        assert_eq!(flushes(OutputBuffering::Full), [6]);
+    }
+
+    #[test]
+    fn borrow_conflicts_are_counted() {
+        let mut program: Program = Program::from_string("region main[1]; region other[1]; proc main: + ^main &main ^other &other ;").unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert_eq!(program.metrics().borrow_conflicts, 2);
+        assert_eq!(program.region("other").unwrap().bytes, [1]);
+    }
+
+    #[test]
+    fn access_counts_cover_reads_and_writes() {
+        let options: ProgramOptions = ProgramOptions { access_tracking: AccessTracking::Histogram, ..ProgramOptions::default() };
+        let mut program: Program = Program::from_string_with_options("region main[2]; region other[1]; proc main: + . > , ^other ;", &options).unwrap();
+        program.run_with_bytes(b"x").unwrap();
+        let stats: &BTreeMap<String, AccessStats> = &program.metrics().regions;
+        assert_eq!(stats["main"], AccessStats { reads: 3, writes: 2, histogram: Some(vec![3, 2]) });
+        assert_eq!(stats["other"], AccessStats { reads: 0, writes: 1, histogram: Some(vec![1]) });
+    }
+
+    #[test]
+    fn folded_profile_has_a_line_per_call_stack() {
+        let options: ProgramOptions = ProgramOptions { profile: true, ..ProgramOptions::default() };
+        let mut program: Program = Program::from_string_with_options("region main[1]; proc main: f + + ; proc f: g + ; proc g: + ;", &options).unwrap();
+        program.run_with_bytes(&[]).unwrap();
+        assert_eq!(program.folded_profile().unwrap(), "main 3\nmain;f 2\nmain;f;g 1\n");
+        assert!(Program::from_string("proc main: + ;").unwrap().folded_profile().is_none());
+    }
+
+    #[test]
+    fn source_map_matches_the_version_1_schema() {
+        let program: Program = Program::from_string("region main[1];\nproc main: + f;\nproc f:\n    .\n;").unwrap();
+        assert_eq!(
+            program.source_map(),
+            "{\"version\":1,\"procedures\":{\"main\":[{\"start\":{\"line\":2,\"column\":12},\"end\":{\"line\":2,\"column\":13}},{\"start\":{\"line\":2,\"column\":14},\"end\":{\"line\":2,\"column\":15}}],\"f\":[{\"start\":{\"line\":4,\"column\":5},\"end\":{\"line\":4,\"column\":6}}]}}",
+        );
+    }
+
+    #[test]
+    fn call_graph_includes_anonymous_blocks() {
+        let program: Program = Program::from_string("region main[1]; region other[1]; proc main: (f)@other g ; proc f: ; proc g: (+) (h)@new[2] ; proc h: ;").unwrap();
+        let graph: BTreeMap<String, BTreeSet<String>> = program.call_graph();
+        let edges: Vec<(&str, Vec<&str>)> = graph.iter().map(|(name, callees)| (name.as_str(), callees.iter().map(String::as_str).collect())).collect();
+        assert_eq!(edges, [
+            ("f", vec![]),
+            ("g", vec!["g-anon-0", "g-anon-1"]),
+            ("g-anon-0", vec![]),
+            ("g-anon-1", vec!["h"]),
+            ("h", vec![]),
+            ("main", vec!["g", "main-anon-0"]),
+            ("main-anon-0", vec!["f"]),
+        ]);
+    }
+
+    #[test]
+    fn call_graph_dot_dashes_anonymous_blocks_and_labels_regions() {
+        let program: Program = Program::from_string("region main[1]; region r[2]; proc main: (f)@r g ; proc f: (g)@$ ; proc g: ;").unwrap();
+        assert_eq!(program.call_graph_dot(), concat!(
+            "digraph calls {\n",
+            "    \"main-anon-0\" [style=dashed];\n",
+            "    \"main\";\n",
+            "    \"f-anon-0\" [style=dashed];\n",
+            "    \"f\";\n",
+            "    \"g\";\n",
+            "    \"main-anon-0\" -> \"f\";\n",
+            "    \"main\" -> \"g\";\n",
+            "    \"main\" -> \"main-anon-0\" [label=\"r\"];\n",
+            "    \"f-anon-0\" -> \"g\";\n",
+            "    \"f\" -> \"f-anon-0\" [label=\"$\"];\n",
+            "}\n",
+        ));
     }
 }
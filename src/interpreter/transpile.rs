@@ -0,0 +1,439 @@
+use alloc::{collections::BTreeMap, format, string::{String, ToString}, vec::Vec};
+use core::{cell::RefCell, fmt::Write};
+
+use crate::{procedure::{InfoKind, Instruction, Procedure, RegionReference, INTERPRETER_ID}, region::{Direction, Region}};
+
+// Everything the generated procedures lean on. Only the default modes are supported: arithmetic and pointer movement
+// wrap, and no region is poisoned. Much like the interpreter, only the region a procedure is running on is ever "in
+// use", which is all the send, receive and transfer checks below need to know. A window is a region of its own that
+// points into the bytes of its base, and since pointers are per struct, the head is copied back and forth across calls
+const PRELUDE: &str = r#"#include <stdint.h>
+#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+
+struct region {
+    const char *name;
+    uint8_t *bytes;
+    size_t len;
+    size_t pointer;
+    struct region *base;
+    size_t offset;
+};
+
+#define CELL(r) ((r)->bytes[(r)->pointer])
+
+enum fold { FOLD_SUM, FOLD_MIN, FOLD_MAX };
+
+static void fail(const char *message, const char *name) {
+    fflush(stdout);
+    fprintf(stderr, "%s: %s\n", message, name);
+    exit(1);
+}
+
+static void right(struct region *r) {
+    r->pointer = (r->pointer + 1 == r->len) ? 0 : r->pointer + 1;
+}
+
+static void left(struct region *r) {
+    r->pointer = (r->pointer == 0) ? r->len - 1 : r->pointer - 1;
+}
+
+static void assert_cell(struct region *r, uint8_t expected) {
+    if (CELL(r) != expected) {
+        fflush(stdout);
+        fprintf(stderr, "assertion failed: expected %u, got %u at cell %zu of %s\n", expected, CELL(r), r->pointer, r->name);
+        exit(1);
+    }
+}
+
+static void seek_zero_right(struct region *r) {
+    for (size_t i = 0; i < r->len; i++) {
+        size_t location = (r->pointer + i) % r->len;
+        if (r->bytes[location] == 0) {
+            r->pointer = location;
+            return;
+        }
+    }
+    fail("no zero cell", r->name);
+}
+
+static void seek_zero_left(struct region *r) {
+    for (size_t i = 0; i < r->len; i++) {
+        size_t location = (r->pointer + r->len - i) % r->len;
+        if (r->bytes[location] == 0) {
+            r->pointer = location;
+            return;
+        }
+    }
+    fail("no zero cell", r->name);
+}
+
+static void rotate_right(struct region *r, size_t distance) {
+    uint8_t *rotated = malloc(r->len);
+    for (size_t i = 0; i < r->len; i++) {
+        rotated[(i + distance) % r->len] = r->bytes[i];
+    }
+    memcpy(r->bytes, rotated, r->len);
+    free(rotated);
+}
+
+static void read_cell(struct region *r) {
+    int c = getchar();
+    if (c != EOF) {
+        CELL(r) = (uint8_t) c;
+    }
+}
+
+static void read_until(struct region *r, int delimiter, int keep_delimiter) {
+    size_t start = r->pointer;
+    uint8_t length = 0;
+    int c;
+    while ((c = getchar()) != EOF) {
+        if (c == delimiter && !keep_delimiter) {
+            break;
+        }
+        right(r);
+        CELL(r) = (uint8_t) c;
+        length++;
+        if (c == delimiter) {
+            break;
+        }
+    }
+    r->pointer = start;
+    CELL(r) = length;
+}
+
+static void send(struct region *r, struct region *other) {
+    if (other != r->base) {
+        CELL(other) = CELL(r);
+    }
+}
+
+static void receive(struct region *r, struct region *other) {
+    if (other != r->base) {
+        CELL(r) = CELL(other);
+    }
+}
+
+static void transfer(struct region *r, struct region *source, struct region *destination) {
+    if (source == destination) {
+        return;
+    }
+    if (source == r->base) {
+        fail("region conflict", source->name);
+    }
+    if (destination == r->base) {
+        fail("region conflict", destination->name);
+    }
+    CELL(destination) = CELL(source);
+}
+
+static void enter_window(struct region *window, struct region *base, size_t start, size_t end) {
+    if (end > base->len) {
+        fail("invalid window", base->name);
+    }
+    size_t pointer = base->pointer;
+    *window = (struct region) {base->name, base->bytes + start, end - start, 0, base, start};
+    if ((pointer >= start) && (pointer < end)) {
+        window->pointer = pointer - start;
+    }
+}
+
+static void leave_window(struct region *window) {
+    window->base->pointer = window->offset + window->pointer;
+}
+
+// Called around every call that leaves the current region, so a window and its base agree on where the head is
+static void sync_out(struct region *r) {
+    if (r->base != r) {
+        leave_window(r);
+    }
+}
+
+static void sync_in(struct region *r) {
+    if (r->base != r) {
+        enter_window(r, r->base, r->offset, r->offset + r->len);
+    }
+}
+
+// Regions made for a single call, as in `proc@new[8]`, which the caller frees once the call returns
+static void new_region(struct region *t, const char *name, size_t len) {
+    *t = (struct region) {name, calloc(len, 1), len, 0, t, 0};
+}
+
+static void reset_region(struct region *r) {
+    memset(r->bytes, 0, r->len);
+    r->pointer = 0;
+}
+
+// A cell always equals itself, even when `r` is only a window on the other region
+static uint8_t other_cell(struct region *r, struct region *other) {
+    return (other == r->base) ? CELL(r) : CELL(other);
+}
+
+static void quote_bytes(struct region *r, const char *bytes, size_t count) {
+    for (size_t i = 0; i < count; i++) {
+        CELL(r) = (uint8_t) bytes[i];
+        right(r);
+    }
+}
+
+static void copy_relative(struct region *r, unsigned long long distance, int leftwards) {
+    size_t step = (size_t) (distance % r->len);
+    r->bytes[leftwards ? ((r->pointer + r->len - step) % r->len) : ((r->pointer + step) % r->len)] = CELL(r);
+}
+
+// Min and max start from the first cell, since every region and window has at least one
+static uint8_t fold_region(struct region *r, enum fold fold) {
+    uint8_t result = (fold == FOLD_SUM) ? 0 : r->bytes[0];
+    for (size_t i = 0; i < r->len; i++) {
+        uint8_t cell = r->bytes[i];
+        if (fold == FOLD_SUM) {
+            result += cell;
+        } else if ((fold == FOLD_MIN) ? (cell < result) : (cell > result)) {
+            result = cell;
+        }
+    }
+    return result;
+}
+
+static void compare(struct region *r, struct region *other) {
+    uint8_t value = other_cell(r, other);
+    CELL(r) = (CELL(r) < value) ? 255 : (CELL(r) == value) ? 0 : 1;
+}
+"#;
+
+// Octal escapes are always three digits, so unlike hex ones they can't run into whatever character follows
+fn c_string(bytes: &[u8]) -> String {
+    let mut literal: String = String::from("\"");
+    for byte in bytes {
+        write!(literal, "\\{:03o}", byte).unwrap();
+    }
+    literal.push('"');
+    return literal;
+}
+
+struct Names<'a> {
+    regions: BTreeMap<&'a str, usize>,
+    procedures: BTreeMap<&'a str, usize>,
+}
+
+impl Names<'_> {
+    fn region(&self, name: &str) -> String {
+        match self.regions.get(name) {
+            Some(index) => return format!("&regions[{}]", index),
+            // Renaming a region can leave a reference dangling, which the interpreter only reports once it's reached
+            None => return format!("(fail(\"unknown region\", {}), r)", c_string(name.as_bytes())),
+        }
+    }
+
+    fn reference(&self, reference: &RegionReference) -> String {
+        match reference {
+            RegionReference::BackReference => return "back".to_string(),
+            RegionReference::Named(name) => return self.region(name),
+        }
+    }
+}
+
+fn emit_instruction(names: &Names, instruction: &Instruction) -> String {
+    match instruction {
+        Instruction::Right => return "right(r);".to_string(),
+        Instruction::Left => return "left(r);".to_string(),
+        Instruction::Reset => return "r->pointer = 0;".to_string(),
+        Instruction::Plus => return "CELL(r)++;".to_string(),
+        Instruction::Minus => return "CELL(r)--;".to_string(),
+        Instruction::LoopStart(_) => return "while (CELL(r)) {".to_string(),
+        Instruction::LoopEnd(_) => return "}".to_string(),
+        Instruction::Else(_) => return "if (!entered) {".to_string(),
+        Instruction::EndElse => return "} }".to_string(),
+        Instruction::SeekZero(Direction::Right) => return "seek_zero_right(r);".to_string(),
+        Instruction::SeekZero(Direction::Left) => return "seek_zero_left(r);".to_string(),
+        Instruction::Rotate(amount) => {
+            let distance: String = format!("(size_t) {}ull % r->len", amount.unsigned_abs());
+            if *amount >= 0 {
+                return format!("rotate_right(r, {});", distance);
+            }
+            return format!("rotate_right(r, (r->len - {}) % r->len);", distance);
+        },
+        Instruction::GotoCell => return "r->pointer = CELL(r) % r->len;".to_string(),
+        Instruction::Read => return "read_cell(r);".to_string(),
+        Instruction::ReadLine => return "read_until(r, '\\n', 1);".to_string(),
+        Instruction::ReadUntil(delimiter) => return format!("read_until(r, {}, 0);", delimiter),
+        Instruction::Write => return "putchar(CELL(r));".to_string(),
+        Instruction::WriteRegion => return "fwrite(r->bytes, 1, r->len, stdout);".to_string(),
+        Instruction::WriteByte(value) => return format!("putchar({});", value),
+        Instruction::WriteLiteral(bytes) => return format!("fwrite({}, 1, {}, stdout);", c_string(bytes), bytes.len()),
+        Instruction::Quote(value) => return format!("CELL(r) = {};", value),
+        Instruction::Assert(expected) => return format!("assert_cell(r, {});", expected),
+        Instruction::QuoteBytes(bytes) => return format!("quote_bytes(r, {}, {});", c_string(bytes), bytes.len()),
+        Instruction::Send(reference) => return format!("send(r, {});", names.reference(reference)),
+        Instruction::Receive(reference) => return format!("receive(r, {});", names.reference(reference)),
+        Instruction::Transfer(source, destination) => {
+            return format!("transfer(r, {}, {});", names.reference(source), names.reference(destination));
+        },
+        Instruction::Compare(reference) => return format!("compare(r, {});", names.reference(reference)),
+        Instruction::Not => return "CELL(r) = ~CELL(r);".to_string(),
+        Instruction::And(reference) => return format!("CELL(r) &= other_cell(r, {});", names.reference(reference)),
+        Instruction::Or(reference) => return format!("CELL(r) |= other_cell(r, {});", names.reference(reference)),
+        Instruction::Xor(reference) => return format!("CELL(r) ^= other_cell(r, {});", names.reference(reference)),
+        // Shifting by at least the width of an int is undefined in C, rather than just clearing the cell
+        Instruction::Shl(amount) | Instruction::Shr(amount) if *amount >= 8 => return "CELL(r) = 0;".to_string(),
+        Instruction::Shl(amount) => return format!("CELL(r) <<= {};", amount),
+        Instruction::Shr(amount) => return format!("CELL(r) >>= {};", amount),
+        Instruction::Mod(divisor) => return format!("CELL(r) %= {};", divisor),
+        Instruction::CopyRel(offset) => return format!("copy_relative(r, {}ull, {});", offset.unsigned_abs(), i32::from(*offset < 0)),
+        Instruction::SumRegion => return "CELL(r) = fold_region(r, FOLD_SUM);".to_string(),
+        Instruction::MinRegion => return "CELL(r) = fold_region(r, FOLD_MIN);".to_string(),
+        Instruction::MaxRegion => return "CELL(r) = fold_region(r, FOLD_MAX);".to_string(),
+        // The same answers the interpreter gives, so a program takes the same branches either way
+        Instruction::RuntimeInfo(InfoKind::Interpreter) => return format!("CELL(r) = {};", INTERPRETER_ID),
+        Instruction::RuntimeInfo(InfoKind::CellWidth) => return "CELL(r) = 8;".to_string(),
+        Instruction::RuntimeInfo(InfoKind::RegionCount) => return format!("CELL(r) = {};", names.regions.len().min(255)),
+        Instruction::ResetScratch(region) => return format!("reset_region({});", names.region(region)),
+        // Plain calls hand over `r` itself, which keeps them inside of whatever window it is
+        Instruction::Call(procedure, None, _, argument) => {
+            let argument: &str = if *argument { "r->bytes[0] = CELL(r); " } else { "" };
+            return format!("{}procedure_{}(r, back);", argument, names.procedures[procedure.as_str()]);
+        },
+        Instruction::Call(procedure, Some(reference), None, argument) => {
+            let argument: String = if *argument { format!("({})->bytes[0] = CELL(r); ", names.reference(reference)) } else { String::new() };
+            return format!("{}sync_out(r); procedure_{}({}, back); sync_in(r);", argument, names.procedures[procedure.as_str()], names.reference(reference));
+        },
+        // The argument can only go in once the window is known to fit, by which point `r` may be the same region
+        Instruction::Call(procedure, Some(reference), Some(window), argument) => {
+            let (read, write): (&str, &str) = if *argument { ("uint8_t value = CELL(r); ", " w.bytes[0] = value;") } else { ("", "") };
+            return format!(
+                "{{ {}sync_out(r); struct region w; enter_window(&w, {}, {}, {});{} procedure_{}(&w, back); leave_window(&w); sync_in(r); }}",
+                read, names.reference(reference), window.start, window.end, write, names.procedures[procedure.as_str()],
+            );
+        },
+        Instruction::CallTransient(procedure, size, argument) => {
+            let (read, write): (&str, &str) = if *argument { ("uint8_t value = CELL(r); ", " t.bytes[0] = value;") } else { ("", "") };
+            let name: String = format!("{}@new", procedure);
+            return format!(
+                "{{ {}sync_out(r); struct region t; new_region(&t, {}, {});{} procedure_{}(&t, back); free(t.bytes); sync_in(r); }}",
+                read, c_string(name.as_bytes()), size, write, names.procedures[procedure.as_str()],
+            );
+        },
+    }
+}
+
+// Regions start out with whatever they hold right now, and each procedure becomes a C function taking the region it
+// runs on along with the back reference. Calls are real C calls, so very deep recursion can overflow the C stack,
+// although compilers will usually turn the trailing calls used for iteration into jumps
+pub(crate) fn to_c(regions: &BTreeMap<String, RefCell<Region>>, region_order: &[String], procedures: &BTreeMap<String, Procedure>, procedure_order: &[String]) -> String {
+    // Declaration order throughout, so the same source always gives the same C
+    let region_names: Vec<&str> = region_order.iter().map(String::as_str).collect();
+    let procedure_names: Vec<&str> = procedure_order.iter().map(String::as_str).collect();
+    let names: Names = Names {
+        regions: region_names.iter().enumerate().map(|(i, name)| (*name, i)).collect(),
+        procedures: procedure_names.iter().enumerate().map(|(i, name)| (*name, i)).collect(),
+    };
+
+    let mut source: String = String::from(PRELUDE);
+    source.push('\n');
+    for (i, name) in region_names.iter().enumerate() {
+        let region = regions[*name].borrow();
+        if region.iter().all(|byte| *byte == 0) {
+            writeln!(source, "static uint8_t bytes_{}[{}];", i, region.len()).unwrap();
+        } else {
+            let values: Vec<String> = region.iter().map(|byte| byte.to_string()).collect();
+            writeln!(source, "static uint8_t bytes_{}[{}] = {{{}}};", i, region.len(), values.join(", ")).unwrap();
+        }
+    }
+    writeln!(source, "\nstatic struct region regions[{}] = {{", region_names.len()).unwrap();
+    for (i, name) in region_names.iter().enumerate() {
+        let region = regions[*name].borrow();
+        writeln!(source, "    {{{}, bytes_{}, {}, {}, &regions[{}], 0}},", c_string(name.as_bytes()), i, region.len(), region.pointer(), i).unwrap();
+    }
+    source.push_str("};\n\n");
+
+    for (i, _) in procedure_names.iter().enumerate() {
+        writeln!(source, "static void procedure_{}(struct region *r, struct region *back);", i).unwrap();
+    }
+    for (i, name) in procedure_names.iter().enumerate() {
+        let procedure: &Procedure = &procedures[*name];
+        writeln!(source, "\n// {}", name).unwrap();
+        writeln!(source, "static void procedure_{}(struct region *r, struct region *back) {{", i).unwrap();
+        // Anonymous procedures share the back reference of the procedure they were written in
+        if !procedure.is_anonymous {
+            source.push_str("    back = r->base;\n");
+        }
+        let mut depth: usize = 1;
+        for index in 0..procedure.len() {
+            let instruction: &Instruction = procedure.instruction(index).unwrap();
+            if let Instruction::LoopEnd(_) | Instruction::EndElse = instruction {
+                depth -= 1;
+            }
+            let mut line: String = emit_instruction(&names, instruction);
+            // A while/else loop remembers whether its body ran in a block of its own, which also scopes the flag
+            if let Instruction::LoopStart(location) = instruction && matches!(procedure.instruction(*location), Some(Instruction::Else(_))) {
+                line.insert_str(0, "{ int entered = CELL(r) != 0; ");
+            }
+            writeln!(source, "{}{}", "    ".repeat(depth), line).unwrap();
+            if let Instruction::LoopStart(_) | Instruction::Else(_) = instruction {
+                depth += 1;
+            }
+        }
+        source.push_str("}\n");
+    }
+
+    source.push_str("\nint main(void) {\n");
+    if names.regions.contains_key("main") && names.procedures.contains_key("main") {
+        let main: String = names.region("main");
+        writeln!(source, "    procedure_{}({}, {});", names.procedures["main"], main, main).unwrap();
+    }
+    source.push_str("    fflush(stdout);\n    return 0;\n}\n");
+    return source;
+}
+
+#[cfg(all(test, feature = "stdio"))]
+mod tests {
+    use std::{fs, io::Write as _, path::{Path, PathBuf}, process::{Child, Command, ExitStatus, Output, Stdio}};
+
+    use crate::interpreter::program::Program;
+
+    // Builds the C translation of an example with whatever `cc` is on the path and runs it on the given input
+    fn run_compiled(program: &Program, name: &str, input: &[u8]) -> Vec<u8> {
+        let directory: PathBuf = std::env::temp_dir().join(format!("caedan-transpile-{}-{}", std::process::id(), name));
+        fs::create_dir_all(&directory).unwrap();
+        let source: PathBuf = directory.join("program.c");
+        let binary: PathBuf = directory.join("program");
+        fs::write(&source, program.to_c()).unwrap();
+        let status: ExitStatus = Command::new("cc").arg("-O2").arg("-o").arg(&binary).arg(&source).status().expect("these tests need a C compiler named cc");
+        assert!(status.success(), "the C translation of {} failed to compile", name);
+        let mut child: Child = Command::new(&binary).stdin(Stdio::piped()).stdout(Stdio::piped()).spawn().unwrap();
+        child.stdin.take().unwrap().write_all(input).unwrap();
+        let output: Output = child.wait_with_output().unwrap();
+        fs::remove_dir_all(&directory).unwrap();
+        assert!(output.status.success());
+        return output.stdout;
+    }
+
+    fn assert_matches_interpreter(name: &str, input: &[u8]) -> () {
+        let path: PathBuf = Path::new(env!("CARGO_MANIFEST_DIR")).join("examples").join(format!("{}.cae", name));
+        let program: Program = Program::from_source(&path).unwrap();
+        let compiled: Vec<u8> = run_compiled(&program, name, input);
+        let interpreted: Vec<u8> = Program::from_source(&path).unwrap().run_with_bytes(input).unwrap();
+        assert_eq!(compiled, interpreted);
+    }
+
+    #[test]
+    #[ignore = "needs a C compiler, run with --ignored"]
+    fn math_example_matches_the_interpreter() {
+        assert_matches_interpreter("math", b"");
+    }
+
+    #[test]
+    #[ignore = "needs a C compiler, run with --ignored"]
+    fn echo_example_matches_the_interpreter() {
+        assert_matches_interpreter("echo", b"Hello, world!\n");
+    }
+
+    #[test]
+    #[ignore = "needs a C compiler, run with --ignored"]
+    fn check_example_matches_the_interpreter() {
+        assert_matches_interpreter("check", b"Hi\n");
+    }
+}
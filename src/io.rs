@@ -0,0 +1,87 @@
+// The little of `std::io` that running a program needs, so that the interpreter also works without `std`. With it,
+// everything implementing the `std::io` traits implements these as well, and the error is the usual `io::Error`.
+// Without it, only byte slices and vectors do, the same way they implement the `std::io` traits
+#[cfg(feature = "std")]
+pub use std::io::Error;
+
+#[cfg(not(feature = "std"))]
+use alloc::vec::Vec;
+
+// Without `std` there is nothing to report beyond the fact that the reader or writer gave up
+#[cfg(not(feature = "std"))]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Error;
+
+pub trait Read {
+    // None once the input has run out
+    fn read_byte(&mut self) -> Result<Option<u8>, Error>;
+}
+
+pub trait Write {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error>;
+    fn flush(&mut self) -> Result<(), Error>;
+}
+
+#[cfg(feature = "std")]
+impl<R: std::io::Read + ?Sized> Read for R {
+    fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+        let mut buf: [u8; 1] = [0; 1];
+        match self.read_exact(&mut buf) {
+            Ok(()) => return Ok(Some(buf[0])),
+            Err(error) if error.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(error) => return Err(error),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<W: std::io::Write + ?Sized> Write for W {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        return std::io::Write::write_all(self, bytes);
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        return std::io::Write::flush(self);
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &[u8] {
+    fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+        let Some((first, rest)) = self.split_first() else {
+            return Ok(None);
+        };
+        *self = rest;
+        return Ok(Some(*first));
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Read for &mut &[u8] {
+    fn read_byte(&mut self) -> Result<Option<u8>, Error> {
+        return (**self).read_byte();
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for Vec<u8> {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.extend_from_slice(bytes);
+        return Ok(());
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        return Ok(());
+    }
+}
+
+#[cfg(not(feature = "std"))]
+impl Write for &mut Vec<u8> {
+    fn write_all(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        return (**self).write_all(bytes);
+    }
+
+    fn flush(&mut self) -> Result<(), Error> {
+        return Ok(());
+    }
+}
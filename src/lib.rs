@@ -0,0 +1,11 @@
+#![cfg_attr(not(feature = "std"), no_std)]
+#![allow(clippy::needless_return)]
+#![allow(clippy::unused_unit)]
+
+extern crate alloc;
+
+pub mod io;
+pub mod procedure;
+pub mod region;
+pub mod interpreter;
+pub mod parser;
@@ -1,16 +1,377 @@
 #![allow(clippy::needless_return)]
 #![allow(clippy::unused_unit)]
 
-mod procedure;
-mod region;
-mod interpreter;
-mod parser;
+mod debugger;
 
-use std::path::PathBuf;
+use std::{io::{self, BufWriter, IsTerminal, Read}, num::NonZeroUsize, path::{Path, PathBuf}, process, sync::atomic::{AtomicBool, Ordering}, time::{Duration, Instant}};
 
-use interpreter::program::Program;
+use caedan::{interpreter::program::{OutputBuffering, OutputEncoding, Program, ProgramOptions, RunLimits, RunOptions, RunOutcome, RuntimeError}, parser::{char_stream::Position, json, parser::{parse, parse_str, ParseError, ParseOptions, ParseResult, ParsedInstruction}}, region::{AccessTracking, PointerHistory}};
+
+// Anonymous procedures are only called from the procedure they were written in, although repetition can copy the
+// call more than once
+fn show_procedures(result: &ParseResult) -> () {
+    for procedure in &result.procedures {
+        let kind: &str = if procedure.is_anonymous { "anonymous" } else { "named" };
+        print!("{} ({}, {} instructions)", procedure.name, kind, procedure.instructions.len());
+        for parent in &result.procedures {
+            for (i, instruction) in parent.instructions.iter().enumerate() {
+                match instruction {
+                    ParsedInstruction::Call(callee, _, _, _) | ParsedInstruction::CallTransient(callee, _, _) if procedure.is_anonymous && (*callee == procedure.name) => {
+                        let start: Position = parent.spans[i].start;
+                        print!(", called from {}:{} at line {}, column {}", parent.name, i, start.line, start.column);
+                    },
+                    _ => {},
+                }
+            }
+        }
+        println!();
+    }
+}
+
+// Most errors don't know where in the source they happened, the ones that do give the line and column
+fn describe_parse_error(error: &ParseError) -> String {
+    let at = |position: &Position| -> String {
+        return format!("line {}, column {}", position.line, position.column);
+    };
+    return match error {
+        ParseError::DuplicateIdentifier => "a region or procedure is declared more than once".to_string(),
+        ParseError::InitializerTooLong { length, size } => format!("an initializer lists {} bytes for a region of {}", length, size),
+        ParseError::InvalidEscape(position) => format!("invalid escape at {}", at(position)),
+        ParseError::InvalidIdentifier => "invalid identifier".to_string(),
+        ParseError::InvalidUtf8(position) => format!("the source isn't valid UTF-8 at {}", at(position)),
+        ParseError::Io(error) => format!("reading the source failed: {}", error),
+        ParseError::MalformedInstruction => "malformed instruction".to_string(),
+        ParseError::MalformedLine => "malformed line".to_string(),
+        ParseError::MalformedNumber => "malformed number".to_string(),
+        ParseError::MalformedProcedureDeclaration => "malformed procedure declaration".to_string(),
+        ParseError::MemoryBudgetExceeded { requested, budget } => format!("the regions need {} bytes in total, more than the budget of {}", requested, budget),
+        ParseError::MissingFile => "the source file can't be opened".to_string(),
+        ParseError::MissingIdentifier => "missing identifier".to_string(),
+        ParseError::MissingKeyword => "missing keyword".to_string(),
+        ParseError::MissingMain => "there is no main procedure".to_string(),
+        ParseError::NestingTooDeep(position) => format!("brackets nested too deeply at {}", at(position)),
+        ParseError::NonAsciiSource(position) => format!("non-ASCII character at {}, which --ascii-only rejects", at(position)),
+        ParseError::RegionTooLarge { requested, maximum } => format!("a region of {} bytes is larger than the maximum of {}", requested, maximum),
+        ParseError::RepeatTooLarge(position) => format!("a repeat at {} unrolls to too many instructions", at(position)),
+        ParseError::UndefinedReference => "a call or transfer names an undefined procedure or region".to_string(),
+        ParseError::UnmatchedLoop(position) => format!("unmatched loop bracket at {}", at(position)),
+        ParseError::WindowOutOfBounds => "a window doesn't fit inside of its region".to_string(),
+    };
+}
+
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+#[cfg(unix)]
+unsafe extern "C" {
+    fn signal(signal: i32, handler: extern "C" fn(i32)) -> usize;
+    fn _exit(status: i32) -> !;
+}
+
+// The first Ctrl-C asks the run to stop at the next instruction. A program waiting on stdin won't get that far, so
+// a second one gives up straight away
+#[cfg(unix)]
+extern "C" fn handle_sigint(_: i32) {
+    if INTERRUPTED.swap(true, Ordering::Relaxed) {
+        unsafe { _exit(130) };
+    }
+}
+
+#[cfg(unix)]
+fn install_sigint_handler() -> () {
+    unsafe { signal(2, handle_sigint) };
+}
+
+#[cfg(not(unix))]
+fn install_sigint_handler() -> () {}
 
 fn main() {
-    let program: Program = Program::from_source(&PathBuf::from("examples/math.cae")).unwrap();
-    program.run();
+    let mut timings: bool = false;
+    let mut debug: bool = false;
+    let mut ascii_only: bool = false;
+    let mut procedures: bool = false;
+    let mut json_ast: bool = false;
+    let mut metrics_json: bool = false;
+    let mut echo_input: bool = false;
+    let mut access_stats: bool = false;
+    let mut pointer_history: PointerHistory = PointerHistory::Off;
+    let mut trace_length: Option<NonZeroUsize> = None;
+    let mut strict: bool = false;
+    let mut no_assert: bool = false;
+    let mut call_graph: bool = false;
+    let mut source_path: PathBuf = PathBuf::from("examples/math.cae");
+    let mut max_output: Option<u64> = None;
+    let mut tape_size: Option<NonZeroUsize> = None;
+    let mut sourcemap_path: Option<PathBuf> = None;
+    let mut input_path: Option<PathBuf> = None;
+    // Empty until the program is known, when it means every region
+    let mut dump_regions: Option<Vec<String>> = None;
+    let mut flamegraph_path: Option<PathBuf> = None;
+    let mut output_encoding: OutputEncoding = OutputEncoding::default();
+    // Like C's stdio, line by line on a terminal and in as few writes as possible otherwise
+    let mut output_buffering: OutputBuffering = if io::stdout().is_terminal() { OutputBuffering::Line } else { OutputBuffering::Full };
+    let mut arguments = std::env::args().skip(1);
+    while let Some(argument) = arguments.next() {
+        match argument.as_str() {
+            "--timings" => timings = true,
+            "--debug" => debug = true,
+            "--ascii-only" => ascii_only = true,
+            "--show-procedures" => procedures = true,
+            "--json-ast" => json_ast = true,
+            "--metrics-json" => metrics_json = true,
+            "--echo-input" => echo_input = true,
+            "--access-stats" => access_stats = true,
+            "--strict" => strict = true,
+            "--no-assert" => no_assert = true,
+            "--output-encoding" => {
+                match arguments.next().as_deref() {
+                    Some("raw") => output_encoding = OutputEncoding::Raw,
+                    Some("codepoint") => output_encoding = OutputEncoding::Codepoint,
+                    _ => {
+                        eprintln!("--output-encoding expects raw or codepoint");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--output-buffering" => {
+                match arguments.next().as_deref() {
+                    Some("none") => output_buffering = OutputBuffering::None,
+                    Some("line") => output_buffering = OutputBuffering::Line,
+                    Some("full") => output_buffering = OutputBuffering::Full,
+                    _ => {
+                        eprintln!("--output-buffering expects none, line or full");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--call-graph" => {
+                match arguments.next().as_deref() {
+                    Some("dot") => call_graph = true,
+                    _ => {
+                        eprintln!("--call-graph expects dot");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--input" => {
+                match arguments.next() {
+                    Some(path) => input_path = Some(PathBuf::from(path)),
+                    None => {
+                        eprintln!("--input expects a file to read from");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--emit-sourcemap" => {
+                match arguments.next() {
+                    Some(path) => sourcemap_path = Some(PathBuf::from(path)),
+                    None => {
+                        eprintln!("--emit-sourcemap expects a file to write to");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--flamegraph" => {
+                match arguments.next() {
+                    Some(path) => flamegraph_path = Some(PathBuf::from(path)),
+                    None => {
+                        eprintln!("--flamegraph expects a file to write to");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--max-output" => {
+                match arguments.next().and_then(|value| value.parse::<u64>().ok()) {
+                    Some(limit) => max_output = Some(limit),
+                    None => {
+                        eprintln!("--max-output expects a number of bytes");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--tape-size" => {
+                match arguments.next().and_then(|value| value.parse::<NonZeroUsize>().ok()) {
+                    Some(size) => tape_size = Some(size),
+                    None => {
+                        eprintln!("--tape-size expects a number of bytes greater than zero");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--pointer-history" => pointer_history = PointerHistory::All,
+            _ if argument.starts_with("--pointer-history=") => {
+                match argument["--pointer-history=".len()..].parse::<NonZeroUsize>() {
+                    Ok(limit) => pointer_history = PointerHistory::Last(limit),
+                    Err(_) => {
+                        eprintln!("--pointer-history= expects a number of positions greater than zero");
+                        process::exit(1);
+                    },
+                }
+            },
+            _ if argument.starts_with("--trace=") => {
+                match argument["--trace=".len()..].parse::<NonZeroUsize>() {
+                    Ok(length) => trace_length = Some(length),
+                    Err(_) => {
+                        eprintln!("--trace= expects a number of instructions greater than zero");
+                        process::exit(1);
+                    },
+                }
+            },
+            "--dump-regions" => dump_regions = Some(Vec::new()),
+            _ if argument.starts_with("--dump-regions=") => {
+                let names: Vec<String> = argument["--dump-regions=".len()..].split(',').filter(|name| !name.is_empty()).map(str::to_string).collect();
+                if names.is_empty() {
+                    eprintln!("--dump-regions= expects a comma separated list of region names");
+                    process::exit(1);
+                }
+                dump_regions = Some(names);
+            },
+            _ if argument.starts_with("--") => {
+                eprintln!("Unknown option {}", argument);
+                process::exit(1);
+            },
+            _ => source_path = PathBuf::from(argument),
+        }
+    }
+
+    // Reading the program from stdin means the program itself will only ever see the end of its input
+    let mut source: Option<String> = None;
+    if source_path == Path::new("-") {
+        let mut text: String = String::new();
+        if let Err(error) = io::stdin().read_to_string(&mut text) {
+            eprintln!("can't read the program from stdin: {}", error);
+            process::exit(1);
+        }
+        source = Some(text);
+    }
+
+    let options: ProgramOptions = ProgramOptions {
+        parse: ParseOptions {
+            ascii_only,
+            preserve_comments: json_ast,
+            default_region_size: tape_size.unwrap_or(ParseOptions::default().default_region_size),
+            ..ParseOptions::default()
+        },
+        output_encoding,
+        profile: flamegraph_path.is_some(),
+        echo_input,
+        ignore_assertions: no_assert,
+        access_tracking: if access_stats { AccessTracking::Counts } else { AccessTracking::Off },
+        pointer_history,
+        trace_length,
+        ..if strict { ProgramOptions::strict() } else { ProgramOptions::default() }
+    };
+    let parse_start: Instant = Instant::now();
+    let parsed: Result<ParseResult, ParseError> = match &source {
+        Some(text) => parse_str(text, &options.parse),
+        None => parse(&source_path, &options.parse),
+    };
+    let result: ParseResult = match parsed {
+        Ok(result) => result,
+        Err(error) => {
+            eprintln!("parse error: {}", describe_parse_error(&error));
+            process::exit(1);
+        },
+    };
+    let parse_time: Duration = parse_start.elapsed();
+    if procedures {
+        show_procedures(&result);
+        return;
+    }
+    if json_ast {
+        println!("{}", json::to_json(&result));
+        return;
+    }
+    let lower_start: Instant = Instant::now();
+    let mut program: Program = Program::from_parse_result(result);
+    let lower_time: Duration = lower_start.elapsed();
+    if timings {
+        eprintln!("parse: {:?}", parse_time);
+        eprintln!("lower: {:?}", lower_time);
+    }
+    program.set_options(&options);
+    if let Some(names) = &mut dump_regions {
+        if names.is_empty() {
+            *names = program.region_names().to_vec();
+        }
+        if let Some(missing) = names.iter().find(|name| program.region(name).is_none()) {
+            eprintln!("--dump-regions: no region named {}", missing);
+            process::exit(1);
+        }
+    }
+    if call_graph {
+        print!("{}", program.call_graph_dot());
+        return;
+    }
+    if let Some(path) = &sourcemap_path && let Err(error) = std::fs::write(path, program.source_map()) {
+        eprintln!("--emit-sourcemap: can't write {}: {}", path.display(), error);
+        process::exit(1);
+    }
+    let mut failed: bool = false;
+    if debug {
+        if let Err(error) = debugger::debug(&mut program) {
+            eprintln!("runtime error: {:?}", error);
+            failed = true;
+        }
+    } else {
+        let limits: RunLimits = RunLimits { max_output, ..RunLimits::default() };
+        install_sigint_handler();
+        let output: BufWriter<io::Stdout> = BufWriter::new(io::stdout());
+        let mut run: RunOptions = RunOptions::new().output(output).buffering(output_buffering).limits(limits).interrupt(&INTERRUPTED);
+        match &input_path {
+            Some(path) => match run.input_file(path) {
+                Ok(with_input) => run = with_input,
+                Err(error) => {
+                    eprintln!("--input: can't open {}: {}", path.display(), error);
+                    process::exit(1);
+                },
+            },
+            None => run = run.input(io::stdin()),
+        }
+        let result: Result<RunOutcome, RuntimeError> = program.run_with_options(run);
+        // Before anything else, since the regions are most interesting when the run failed
+        for name in dump_regions.iter().flatten() {
+            debugger::print_region(&mut io::stderr(), &program.region_view(name).unwrap()).unwrap();
+        }
+        for name in program.region_names() {
+            let Some(history) = program.pointer_history(name) else {
+                continue;
+            };
+            let positions: Vec<String> = history.iter().map(usize::to_string).collect();
+            eprintln!("{}: {}", name, positions.join(" "));
+        }
+        if let (Err(_), Some(trace)) = (&result, program.last_trace()) {
+            for entry in trace {
+                let instruction: String = match program.instruction_at(&entry.procedure, entry.instruction) {
+                    Some(instruction) => format!("{:?}", instruction),
+                    None => "end of procedure".to_string(),
+                };
+                eprintln!("{}:{} on {} at {} ({:02x}): {}", entry.procedure, entry.instruction, entry.region, entry.pointer, entry.cell, instruction);
+            }
+        }
+        if let Err(RuntimeError::Interrupted { procedure, instruction, region, pointer }) = &result {
+            eprintln!("interrupted at {}:{} on {}, with the pointer at {}", procedure, instruction, region, pointer);
+            process::exit(130);
+        }
+        // The diagnostics below still get written, since they're often what explains the error
+        if let Err(error) = &result {
+            eprintln!("runtime error: {:?}", error);
+            failed = true;
+        }
+    }
+    if let (Some(path), Some(profile)) = (&flamegraph_path, program.folded_profile()) && let Err(error) = std::fs::write(path, profile) {
+        eprintln!("--flamegraph: can't write {}: {}", path.display(), error);
+        process::exit(1);
+    }
+    // Written to stderr like the timings, so that it never mixes with the program's own output
+    if access_stats {
+        for (name, stats) in &program.metrics().regions {
+            eprintln!("{}: {} reads, {} writes", name, stats.reads, stats.writes);
+        }
+    }
+    if metrics_json {
+        eprintln!("{}", program.metrics().to_json());
+    }
+    if failed {
+        process::exit(1);
+    }
 }
@@ -1,43 +1,194 @@
-use std::io::{Bytes, Read};
+use alloc::collections::VecDeque;
 
+use crate::{io::Read, parser::parser::ParseError};
+
+// Lines and columns count from 1, the offset is in bytes from the start of the source
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+// A CRLF pair comes out as a single `\n`, so that sources written on Windows parse exactly like Unix ones, text
+// literals spanning lines included. A lone `\r` is left as it is
 pub struct CharStream<R: Read> {
-    source: Bytes<R>,
-    buffer: Option<char>,
+    source: R,
+    // Characters peeked at but not consumed yet, along with how many bytes of the source each took up
+    buffer: VecDeque<(char, usize)>,
+    // Whatever followed a `\r` that turned out not to start a CRLF pair
+    lookahead: Option<char>,
+    // The source failing to read or not being valid UTF-8 ends the stream like running out would, with the error kept
+    // for the caller
+    error: Option<ParseError>,
+    position: Position,
+    // Where the next character to be decoded starts, ahead of `position` by whatever has been peeked at
+    decoded: Position,
 }
 
 impl<R: Read> CharStream<R> {
     pub fn new(source: R) -> CharStream<R> {
         return CharStream {
-            source: source.bytes(),
-            buffer: None,
+            source,
+            buffer: VecDeque::new(),
+            lookahead: None,
+            error: None,
+            position: Position { offset: 0, line: 1, column: 1 },
+            decoded: Position { offset: 0, line: 1, column: 1 },
         };
     }
 
-    pub fn next(&mut self) -> Option<char> {
-        if let Some(c) = self.buffer {
-            self.buffer = None;
-            return Some(c);
+    pub fn take_error(&mut self) -> Option<ParseError> {
+        return self.error.take();
+    }
+
+    // The position of the next character to be consumed
+    pub fn position(&self) -> Position {
+        return self.position;
+    }
+
+    // A byte that can't start or continue a character, or a character cut short by the end of the source, is an error
+    // at the position the character would have started at
+    fn decode_char(&mut self) -> Option<char> {
+        let mut buf: [u8; 4] = [0; 4];
+        let mut length: usize = 0;
+        loop {
+            buf[length] = match self.source.read_byte() {
+                Ok(Some(byte)) => byte,
+                Ok(None) if length == 0 => return None,
+                Ok(None) => {
+                    self.error = Some(ParseError::InvalidUtf8(self.decoded));
+                    return None;
+                },
+                Err(error) => {
+                    self.error = Some(ParseError::Io(error));
+                    return None;
+                },
+            };
+            length += 1;
+            match core::str::from_utf8(&buf[..length]) {
+                Ok(decoded) => {
+                    let c: char = decoded.chars().next()?;
+                    self.decoded.offset += length;
+                    if c == '\n' {
+                        self.decoded.line += 1;
+                        self.decoded.column = 1;
+                    } else {
+                        self.decoded.column += 1;
+                    }
+                    return Some(c);
+                },
+                // Only a sequence that is valid so far, but incomplete, is worth reading another byte for
+                Err(error) if error.error_len().is_none() && length < buf.len() => (),
+                Err(_) => {
+                    self.error = Some(ParseError::InvalidUtf8(self.decoded));
+                    return None;
+                },
+            }
         }
-        let mut buf: [u8; 4] = [255, 0, 0, 0];
-        let mut last: usize = 0;
-        while std::str::from_utf8(&buf[0..=last]).is_err() {
-            buf[last] = self.source.next()?.unwrap();
-            last += 1;
-            if last == (buf.len() + 1) {
-                panic!("Source is not valid utf-8");
+    }
+
+    fn decode(&mut self) -> Option<(char, usize)> {
+        let c: char = match self.lookahead.take() {
+            Some(c) => c,
+            None => self.decode_char()?,
+        };
+        if c == '\r' {
+            match self.decode_char() {
+                Some('\n') => return Some(('\n', 2)),
+                next => self.lookahead = next,
             }
         }
-        return unsafe { std::str::from_utf8_unchecked(&buf[0..=last]).chars().nth(0) };
+        return Some((c, c.len_utf8()));
+    }
+
+    pub fn next_char(&mut self) -> Option<char> {
+        let (c, width): (char, usize) = match self.buffer.pop_front() {
+            Some(buffered) => buffered,
+            None => self.decode()?,
+        };
+        self.position.offset += width;
+        if c == '\n' {
+            self.position.line += 1;
+            self.position.column = 1;
+        } else {
+            self.position.column += 1;
+        }
+        return Some(c);
     }
 
     pub fn peek(&mut self) -> Option<char> {
-        if self.buffer.is_none() {
-            self.buffer = self.next();
+        return self.peek_nth(0);
+    }
+
+    // The character `n` places past the next one, without consuming anything
+    pub fn peek_nth(&mut self, n: usize) -> Option<char> {
+        while self.buffer.len() <= n {
+            let decoded: (char, usize) = self.decode()?;
+            self.buffer.push_back(decoded);
         }
-        return self.buffer;
+        return Some(self.buffer[n].0);
     }
 
     pub fn advance(&mut self) -> () {
-        _ = self.next();
+        _ = self.next_char();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::string::String;
+
+    fn read_all(source: &[u8]) -> (String, Position) {
+        let mut stream: CharStream<&[u8]> = CharStream::new(source);
+        let mut text: String = String::new();
+        while let Some(c) = stream.next_char() {
+            text.push(c);
+        }
+        return (text, stream.position());
+    }
+
+    #[test]
+    fn crlf_reads_as_a_single_newline() {
+        let (text, position): (String, Position) = read_all(b"a\r\nb\r\n");
+        assert_eq!(text, "a\nb\n");
+        assert_eq!(position, Position { offset: 6, line: 3, column: 1 });
+    }
+
+    #[test]
+    fn lone_carriage_returns_are_kept() {
+        let (text, position): (String, Position) = read_all(b"a\rb\r");
+        assert_eq!(text, "a\rb\r");
+        assert_eq!(position, Position { offset: 4, line: 1, column: 5 });
+    }
+
+    #[test]
+    fn multibyte_characters_take_up_their_width() {
+        let (text, position): (String, Position) = read_all("é€😀\n".as_bytes());
+        assert_eq!(text, "é€😀\n");
+        assert_eq!(position, Position { offset: 10, line: 2, column: 1 });
+    }
+
+    #[test]
+    fn invalid_bytes_stop_the_stream_with_their_position() {
+        let mut stream: CharStream<&[u8]> = CharStream::new(b"ab\n\xff\xff\xff\xff\xff\xffcd");
+        assert_eq!(stream.peek_nth(2), Some('\n'));
+        assert_eq!(stream.peek_nth(3), None);
+        assert!(matches!(stream.take_error(), Some(ParseError::InvalidUtf8(Position { offset: 3, line: 2, column: 1 }))));
+    }
+
+    #[test]
+    fn truncated_characters_are_errors_rather_than_the_end() {
+        let mut stream: CharStream<&[u8]> = CharStream::new(b"a\xe2\x82");
+        assert_eq!(stream.next_char(), Some('a'));
+        assert_eq!(stream.next_char(), None);
+        assert!(matches!(stream.take_error(), Some(ParseError::InvalidUtf8(Position { offset: 1, line: 1, column: 2 }))));
     }
 }
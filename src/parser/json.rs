@@ -0,0 +1,133 @@
+use alloc::{format, string::{String, ToString}, vec::Vec};
+use core::fmt::Write;
+
+use crate::{parser::{char_stream::Span, parser::{ParseResult, ParsedInstruction, ParsedProcedure}}, procedure::{InfoKind, RegionReference}, region::Direction};
+
+// Procedure names are identifiers, but the ones made up for anonymous blocks and whatever a caller passes to
+// `run_pipeline` aren't checked, so they are escaped anyway. Shared by everything that writes JSON by hand
+pub(crate) fn json_string(text: &str) -> String {
+    let mut escaped: String = String::from("\"");
+    for character in text.chars() {
+        match character {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            _ if character.is_control() => write!(escaped, "\\u{:04x}", character as u32).unwrap(),
+            _ => escaped.push(character),
+        }
+    }
+    escaped.push('"');
+    return escaped;
+}
+
+pub(crate) fn span_json(span: &Span) -> String {
+    return format!(
+        "{{\"start\":{{\"line\":{},\"column\":{}}},\"end\":{{\"line\":{},\"column\":{}}}}}",
+        span.start.line, span.start.column, span.end.line, span.end.column,
+    );
+}
+
+fn reference_json(reference: &RegionReference) -> String {
+    match reference {
+        RegionReference::BackReference => return "{\"kind\":\"back\"}".to_string(),
+        RegionReference::Named(name) => return format!("{{\"kind\":\"named\",\"name\":{}}}", json_string(name)),
+    }
+}
+
+// Every instruction is an object with an `op`, plus whatever operands that op has
+fn instruction_json(instruction: &ParsedInstruction) -> String {
+    let (op, operands): (&str, String) = match instruction {
+        ParsedInstruction::Right => ("right", String::new()),
+        ParsedInstruction::Left => ("left", String::new()),
+        ParsedInstruction::Reset => ("reset", String::new()),
+        ParsedInstruction::Plus => ("plus", String::new()),
+        ParsedInstruction::Minus => ("minus", String::new()),
+        ParsedInstruction::LoopStart => ("loop_start", String::new()),
+        ParsedInstruction::LoopEnd => ("loop_end", String::new()),
+        ParsedInstruction::ElseStart => ("else_start", String::new()),
+        ParsedInstruction::ElseEnd => ("else_end", String::new()),
+        ParsedInstruction::SeekZero(Direction::Right) => ("seek_zero", ",\"direction\":\"right\"".to_string()),
+        ParsedInstruction::SeekZero(Direction::Left) => ("seek_zero", ",\"direction\":\"left\"".to_string()),
+        ParsedInstruction::Rotate(amount) => ("rotate", format!(",\"amount\":{}", amount)),
+        ParsedInstruction::GotoCell => ("goto", String::new()),
+        ParsedInstruction::Read => ("read", String::new()),
+        ParsedInstruction::ReadLine => ("read_line", String::new()),
+        ParsedInstruction::ReadUntil(delimiter) => ("read_until", format!(",\"delimiter\":{}", delimiter)),
+        ParsedInstruction::Write => ("write", String::new()),
+        ParsedInstruction::WriteRegion => ("write_region", String::new()),
+        ParsedInstruction::Newline => ("newline", String::new()),
+        ParsedInstruction::WriteLiteral(bytes) => {
+            let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            ("write_literal", format!(",\"bytes\":[{}]", bytes.join(",")))
+        },
+        ParsedInstruction::Quote(value) => ("quote", format!(",\"value\":{}", value)),
+        ParsedInstruction::Assert(expected) => ("assert", format!(",\"expected\":{}", expected)),
+        ParsedInstruction::QuoteBytes(bytes) => {
+            let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            ("quote_bytes", format!(",\"bytes\":[{}]", bytes.join(",")))
+        },
+        ParsedInstruction::Send(reference) => ("send", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Receive(reference) => ("receive", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Transfer(source, destination) => {
+            ("transfer", format!(",\"source\":{},\"destination\":{}", reference_json(source), reference_json(destination)))
+        },
+        ParsedInstruction::Compare(reference) => ("compare", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Not => ("not", String::new()),
+        ParsedInstruction::And(reference) => ("and", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Or(reference) => ("or", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Xor(reference) => ("xor", format!(",\"region\":{}", reference_json(reference))),
+        ParsedInstruction::Shl(amount) => ("shl", format!(",\"amount\":{}", amount)),
+        ParsedInstruction::Shr(amount) => ("shr", format!(",\"amount\":{}", amount)),
+        ParsedInstruction::Mod(divisor) => ("mod", format!(",\"divisor\":{}", divisor)),
+        ParsedInstruction::CopyRel(offset) => ("copy", format!(",\"offset\":{}", offset)),
+        ParsedInstruction::SumRegion => ("sum", String::new()),
+        ParsedInstruction::MinRegion => ("min", String::new()),
+        ParsedInstruction::MaxRegion => ("max", String::new()),
+        ParsedInstruction::RuntimeInfo(InfoKind::Interpreter) => ("info", ",\"kind\":\"id\"".to_string()),
+        ParsedInstruction::RuntimeInfo(InfoKind::CellWidth) => ("info", ",\"kind\":\"width\"".to_string()),
+        ParsedInstruction::RuntimeInfo(InfoKind::RegionCount) => ("info", ",\"kind\":\"regions\"".to_string()),
+        ParsedInstruction::Call(procedure, region, window, argument) => {
+            let region: String = region.as_ref().map_or("null".to_string(), reference_json);
+            let window: String = window.as_ref().map_or("null".to_string(), |window| format!("{{\"start\":{},\"end\":{}}}", window.start, window.end));
+            ("call", format!(",\"procedure\":{},\"region\":{},\"window\":{},\"argument\":{}", json_string(procedure), region, window, argument))
+        },
+        ParsedInstruction::CallTransient(procedure, size, argument) => {
+            let region: String = format!("{{\"kind\":\"new\",\"size\":{}}}", size);
+            ("call", format!(",\"procedure\":{},\"region\":{},\"window\":null,\"argument\":{}", json_string(procedure), region, argument))
+        },
+        ParsedInstruction::ResetScratch(region) => ("reset_scratch", format!(",\"region\":{}", json_string(region))),
+    };
+    return format!("{{\"op\":\"{}\"{}}}", op, operands);
+}
+
+fn procedure_json(procedure: &ParsedProcedure) -> String {
+    let instructions: Vec<String> = procedure.instructions.iter().map(instruction_json).collect();
+    let spans: Vec<String> = procedure.spans.iter().map(span_json).collect();
+    return format!(
+        "{{\"name\":{},\"anonymous\":{},\"instructions\":[{}],\"spans\":[{}]}}",
+        json_string(&procedure.name), procedure.is_anonymous, instructions.join(","), spans.join(","),
+    );
+}
+
+// The parsed program as a single line of JSON, for tools written in other languages. Regions and procedures keep
+// the order they were parsed in, which puts anonymous procedures right before the procedure they were written in
+pub fn to_json(result: &ParseResult) -> String {
+    let regions: Vec<String> = result.regions.iter().map(|region| {
+        let initializer: String = region.initial_bytes.as_ref().map_or("null".to_string(), |bytes| {
+            let bytes: Vec<String> = bytes.iter().map(|byte| byte.to_string()).collect();
+            return format!("[{}]", bytes.join(","));
+        });
+        return format!(
+            "{{\"name\":{},\"size\":{},\"readonly\":{},\"initializer\":{}}}",
+            json_string(&region.name), region.size, region.readonly, initializer,
+        );
+    }).collect();
+    let aliases: Vec<String> = result.aliases.iter().map(|(name, region)| format!("{}:{}", json_string(name), json_string(region))).collect();
+    let procedures: Vec<String> = result.procedures.iter().map(procedure_json).collect();
+    let comments: Vec<String> = result.comments.iter().map(|comment| {
+        return format!("{{\"text\":{},\"span\":{}}}", json_string(&comment.text), span_json(&comment.span));
+    }).collect();
+    return format!(
+        "{{\"version\":1,\"regions\":[{}],\"aliases\":{{{}}},\"procedures\":[{}],\"comments\":[{}]}}",
+        regions.join(","), aliases.join(","), procedures.join(","), comments.join(","),
+    );
+}
@@ -0,0 +1,106 @@
+use alloc::{collections::{BTreeMap, BTreeSet}, string::String, vec, vec::Vec};
+
+use crate::parser::parser::{ParseResult, ParsedInstruction, ParsedProcedure, ReferencedItem};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Lint {
+    EmptyProcedure(String),
+    PossibleInfiniteRecursion(String),
+}
+
+fn reachable_procedures(result: &ParseResult) -> BTreeSet<&str> {
+    let procedures: BTreeMap<&str, &ParsedProcedure> = result.procedures.iter().map(|procedure| (procedure.name.as_str(), procedure)).collect();
+    let mut reachable: BTreeSet<&str> = BTreeSet::new();
+    let mut pending: Vec<&str> = vec!["main"];
+    while let Some(name) = pending.pop() {
+        let Some(procedure) = procedures.get(name) else {
+            continue;
+        };
+        if !reachable.insert(&procedure.name) {
+            continue;
+        }
+        for reference in procedure.get_all_references() {
+            if let ReferencedItem::Procedure(callee) = reference {
+                pending.push(callee);
+            }
+        }
+    }
+    return reachable;
+}
+
+// Only the obvious case, where nothing before the final call to itself could stop the procedure, wait on input, or
+// even produce any output. A call to anything else could do any of those, so it counts as a way out as well
+fn always_recurses(procedure: &ParsedProcedure) -> bool {
+    let Some((ParsedInstruction::Call(callee, _, _, _) | ParsedInstruction::CallTransient(callee, _, _), rest)) = procedure.instructions.split_last() else {
+        return false;
+    };
+    if *callee != procedure.name {
+        return false;
+    }
+    return rest.iter().all(|instruction| !matches!(instruction,
+        ParsedInstruction::LoopStart |
+        ParsedInstruction::LoopEnd |
+        ParsedInstruction::ElseStart |
+        ParsedInstruction::ElseEnd |
+        ParsedInstruction::Read |
+        ParsedInstruction::ReadLine |
+        ParsedInstruction::ReadUntil(_) |
+        ParsedInstruction::Write |
+        ParsedInstruction::Newline |
+        ParsedInstruction::WriteLiteral(_) |
+        ParsedInstruction::Assert(_) |
+        ParsedInstruction::Call(_, _, _, _) |
+        ParsedInstruction::CallTransient(_, _, _)
+    ));
+}
+
+// Advisory only, none of these stop a program from running
+pub fn lint(result: &ParseResult) -> Vec<Lint> {
+    let reachable: BTreeSet<&str> = reachable_procedures(result);
+    let mut lints: Vec<Lint> = Vec::new();
+    for procedure in &result.procedures {
+        if reachable.contains(procedure.name.as_str()) && procedure.instructions.is_empty() {
+            lints.push(Lint::EmptyProcedure(procedure.name.clone()));
+        }
+        if reachable.contains(procedure.name.as_str()) && always_recurses(procedure) {
+            lints.push(Lint::PossibleInfiniteRecursion(procedure.name.clone()));
+        }
+    }
+    return lints;
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::{string::ToString, vec::Vec};
+
+    use super::*;
+    use crate::parser::parser::{parse_str, ParseOptions};
+
+    fn lints(source: &str) -> Vec<Lint> {
+        return lint(&parse_str(source, &ParseOptions::default()).unwrap());
+    }
+
+    #[test]
+    fn flags_unconditional_self_calls() {
+        assert_eq!(lints("proc main: main;"), [Lint::PossibleInfiniteRecursion("main".to_string())]);
+        assert_eq!(lints("proc main: + > main;"), [Lint::PossibleInfiniteRecursion("main".to_string())]);
+    }
+
+    #[test]
+    fn loops_io_and_other_calls_are_a_way_out() {
+        assert_eq!(lints("proc main: [main];"), []);
+        assert_eq!(lints("proc main: +[-] main;"), []);
+        assert_eq!(lints("proc main: , main;"), []);
+        assert_eq!(lints("proc main: f main; proc f: +;"), []);
+    }
+
+    #[test]
+    fn flags_empty_procedures_that_are_called() {
+        assert_eq!(lints("proc main: f; proc f: ;"), [Lint::EmptyProcedure("f".to_string())]);
+    }
+
+    #[test]
+    fn ignores_unreachable_procedures() {
+        assert_eq!(lints("proc main: +; proc never: never; proc unused: ;"), []);
+    }
+}
@@ -1,2 +1,6 @@
+#[allow(clippy::module_inception)]
 pub mod parser;
 pub mod char_stream;
+pub mod json;
+pub mod lint;
+pub mod visitor;
@@ -1,22 +1,75 @@
-use std::{collections::HashSet, fs::File, num::NonZeroUsize, path::Path, str::FromStr};
+use alloc::{collections::{BTreeMap, BTreeSet}, format, string::{String, ToString}, vec::Vec};
+use core::{num::NonZeroUsize, ops::Range, str::FromStr};
+#[cfg(feature = "stdio")]
+use std::{fs::File, path::Path};
 
-use crate::{parser::char_stream::CharStream, procedure::RegionReference};
+use crate::{io::{Error, Read}, parser::char_stream::{CharStream, Position, Span}, procedure::{InfoKind, RegionReference}, region::Direction};
 
 #[derive(Debug)]
 pub enum ParseError {
     DuplicateIdentifier,
+    InitializerTooLong { length: usize, size: usize },
+    InvalidEscape(Position),
     InvalidIdentifier,
+    // The start of the first character that isn't valid UTF-8
+    InvalidUtf8(Position),
+    // Reading the source failed, rather than it running out
+    Io(Error),
     MalformedInstruction,
     MalformedLine,
     MalformedNumber,
     MalformedProcedureDeclaration,
+    MemoryBudgetExceeded { requested: usize, budget: usize },
     MissingFile,
     MissingIdentifier,
     MissingKeyword,
+    MissingMain,
+    // The position of the first bracket past the limit
+    NestingTooDeep(Position),
+    NonAsciiSource(Position),
+    RegionTooLarge { requested: usize, maximum: usize },
+    // The position of the count that would have unrolled past the limit
+    RepeatTooLarge(Position),
     UndefinedReference,
+    UnmatchedLoop(Position),
+    WindowOutOfBounds,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+pub struct ParseOptions {
+    // In bytes, checked per region so that untrusted sources can't ask for arbitrarily large allocations
+    pub max_region_size: usize,
+    // The total across every region, since many moderately sized regions add up just as well
+    pub memory_budget: usize,
+    // Used for the `main` region when the source doesn't declare one
+    pub default_region_size: NonZeroUsize,
+    // Rejects the whole source before parsing if it contains a single non-ASCII byte
+    pub ascii_only: bool,
+    // Keeps the text of every comment in the result, for tools that have to reproduce the source
+    pub preserve_comments: bool,
+    // How many round brackets can be open at once. Each one is parsed by a recursive call, so generated input could
+    // otherwise overflow the stack
+    pub max_nesting_depth: usize,
+    // How many instructions a single procedure or block can hold once its repeats are unrolled, since a couple of
+    // nested counts can otherwise ask for more than fits in memory
+    pub max_unrolled_instructions: usize,
+}
+
+impl Default for ParseOptions {
+    fn default() -> ParseOptions {
+        return ParseOptions {
+            max_region_size: 1 << 30,
+            memory_budget: 1 << 31,
+            default_region_size: NonZeroUsize::new(30000).unwrap(),
+            ascii_only: false,
+            preserve_comments: false,
+            max_nesting_depth: 256,
+            max_unrolled_instructions: 1 << 24,
+        };
+    }
+}
+
+#[derive(Debug, Clone)]
 pub enum ParsedInstruction {
     Right,
     Left,
@@ -25,18 +78,63 @@ pub enum ParsedInstruction {
     Minus,
     LoopStart,
     LoopEnd,
+    // The braces of `[...]{...}`, which only ever directly follow a loop
+    ElseStart,
+    ElseEnd,
+    SeekZero(Direction),
+    Rotate(isize),
+    GotoCell,
     Read,
+    ReadLine,
+    ReadUntil(u8),
     Write,
+    WriteRegion,
+    Newline,
+    WriteLiteral(Vec<u8>),
     Quote(u8),
+    // Quoted one after the other, moving right after each
+    QuoteBytes(Vec<u8>),
+    Assert(u8),
     Send(RegionReference),
     Receive(RegionReference),
-    Call(String, Option<RegionReference>),
+    Transfer(RegionReference, RegionReference),
+    Compare(RegionReference),
+    Not,
+    And(RegionReference),
+    Or(RegionReference),
+    Xor(RegionReference),
+    Shl(u8),
+    Shr(u8),
+    // Never zero, which is rejected while parsing
+    Mod(u8),
+    CopyRel(isize),
+    SumRegion,
+    MinRegion,
+    MaxRegion,
+    RuntimeInfo(InfoKind),
+    // The range given when calling on part of a region, as in `proc@region[2:6]`, and whether the current cell is
+    // passed along, as in `proc!@region`
+    Call(String, Option<RegionReference>, Option<Range<usize>>, bool),
+    // A call on a region of the given size made just for it, as in `proc@new[8]`
+    CallTransient(String, NonZeroUsize, bool),
+    // Only ever generated, right after the call that used a scratch region
+    ResetScratch(String),
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ParsedRegion {
     pub name: String,
     pub size: NonZeroUsize,
+    // The bytes given in `= {...}`, which may be fewer than the size
+    pub initial_bytes: Option<Vec<u8>>,
+    pub readonly: bool,
+}
+
+// Everything after the `#` up to the end of the line. The span covers the `#` as well
+#[derive(Debug)]
+pub struct ParsedComment {
+    pub text: String,
+    pub span: Span,
 }
 
 #[derive(Debug)]
@@ -44,12 +142,18 @@ pub struct ParsedProcedure {
     pub name: String,
     pub is_anonymous: bool,
     pub instructions: Vec<ParsedInstruction>,
+    // Where each instruction came from in the source, in step with `instructions`
+    pub spans: Vec<Span>,
 }
 
 #[derive(Debug)]
 pub struct ParseResult {
     pub regions: Vec<ParsedRegion>,
     pub procedures: Vec<ParsedProcedure>,
+    // Alternative names for declared regions, from `alias <name> = <region>;`
+    pub aliases: BTreeMap<String, String>,
+    // Only filled in with `preserve_comments`, in source order
+    pub comments: Vec<ParsedComment>,
 }
 
 #[derive(Debug)]
@@ -63,10 +167,18 @@ impl ParseResult {
         return ParseResult {
             regions: Vec::new(),
             procedures: Vec::new(),
+            aliases: BTreeMap::new(),
+            comments: Vec::new(),
         }
     }
 }
 
+impl Default for ParseResult {
+    fn default() -> ParseResult {
+        return ParseResult::new();
+    }
+}
+
 impl ParsedProcedure {
     pub fn get_all_references(&self) -> Vec<ReferencedItem<'_>> {
         let mut references: Vec<ReferencedItem> = Vec::new();
@@ -74,8 +186,22 @@ impl ParsedProcedure {
             match instruction {
                 ParsedInstruction::Send(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
                 ParsedInstruction::Receive(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
-                ParsedInstruction::Call(procedure, None) => references.push(ReferencedItem::Procedure(procedure)),
-                ParsedInstruction::Call(procedure, Some(RegionReference::Named(region))) => {
+                ParsedInstruction::Compare(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
+                ParsedInstruction::And(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
+                ParsedInstruction::Or(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
+                ParsedInstruction::Xor(RegionReference::Named(region)) => references.push(ReferencedItem::Region(region)),
+                ParsedInstruction::ResetScratch(region) => references.push(ReferencedItem::Region(region)),
+                ParsedInstruction::Transfer(source, destination) => {
+                    for reference in [source, destination] {
+                        if let RegionReference::Named(region) = reference {
+                            references.push(ReferencedItem::Region(region));
+                        }
+                    }
+                },
+                ParsedInstruction::Call(procedure, None, _, _) => references.push(ReferencedItem::Procedure(procedure)),
+                ParsedInstruction::CallTransient(procedure, _, _) => references.push(ReferencedItem::Procedure(procedure)),
+                ParsedInstruction::Call(procedure, Some(RegionReference::BackReference), _, _) => references.push(ReferencedItem::Procedure(procedure)),
+                ParsedInstruction::Call(procedure, Some(RegionReference::Named(region)), _, _) => {
                     references.push(ReferencedItem::Procedure(procedure));
                     references.push(ReferencedItem::Region(region));
                 },
@@ -84,6 +210,28 @@ impl ParsedProcedure {
         }
         return references;
     }
+
+    // Rewrites every reference to an alias into one to the region it stands for, so nothing past the parser has to
+    // know about them
+    pub fn resolve_aliases(&mut self, aliases: &BTreeMap<String, String>) -> () {
+        for instruction in &mut self.instructions {
+            let references: Vec<&mut RegionReference> = match instruction {
+                ParsedInstruction::Send(reference) | ParsedInstruction::Receive(reference) | ParsedInstruction::Compare(reference)
+                    | ParsedInstruction::And(reference) | ParsedInstruction::Or(reference) | ParsedInstruction::Xor(reference)
+                    | ParsedInstruction::Call(_, Some(reference), _, _) => Vec::from([reference]),
+                ParsedInstruction::Transfer(source, destination) => Vec::from([source, destination]),
+                _ => Vec::new(),
+            };
+            for reference in references {
+                let RegionReference::Named(name) = reference else {
+                    continue;
+                };
+                if let Some(region) = aliases.get(name.as_str()) {
+                    *name = region.clone();
+                }
+            }
+        }
+    }
 }
 
 fn is_identifier_char(c: char) -> bool {
@@ -100,14 +248,21 @@ fn is_instruction_char(c: char) -> bool {
         (c == '-') ||
         (c == '[') ||
         (c == ']') ||
+        (c == '{') ||
+        (c == '}') ||
+        (c == '?') ||
+        (c == '*') ||
         (c == ',') ||
         (c == '.') ||
+        (c == '/') ||
+        (c == '\'') ||
         (c == '"') ||
+        (c == '=') ||
         (c == '^') ||
         (c == '&');
 }
 
-fn skip_whitespace(stream: &mut CharStream<File>) -> () {
+fn skip_whitespace<R: Read>(stream: &mut CharStream<R>) -> () {
     loop {
         match stream.peek() {
             Some(c) if c.is_whitespace() => stream.advance(),
@@ -116,7 +271,7 @@ fn skip_whitespace(stream: &mut CharStream<File>) -> () {
     }
 }
 
-fn skip_comment(stream: &mut CharStream<File>) -> () {
+fn skip_comment<R: Read>(stream: &mut CharStream<R>) -> () {
     loop {
         match stream.peek() {
             Some('\n') | None => break,
@@ -126,16 +281,39 @@ fn skip_comment(stream: &mut CharStream<File>) -> () {
     stream.advance();
 }
 
-fn expect_keyword(stream: &mut CharStream<File>, keyword: &str) -> Result<(), ParseError> {
+fn parse_comment<R: Read>(stream: &mut CharStream<R>) -> ParsedComment {
+    let start: Position = stream.position();
+    stream.advance();
+    let mut text: String = String::new();
+    loop {
+        match stream.peek() {
+            Some('\n') | None => break,
+            Some(c) => {
+                text.push(c);
+                stream.advance();
+            },
+        }
+    }
+    let span: Span = Span { start, end: stream.position() };
+    stream.advance();
+    return ParsedComment { text, span };
+}
+
+fn expect_keyword<R: Read>(stream: &mut CharStream<R>, keyword: &str) -> Result<(), ParseError> {
     for keyword_c in keyword.chars() {
-        if stream.next().ok_or(ParseError::MissingKeyword)? != keyword_c {
+        if stream.next_char().ok_or(ParseError::MissingKeyword)? != keyword_c {
             return Err(ParseError::MissingKeyword);
         }
     }
     return Ok(());
 }
 
-fn parse_identifier(stream: &mut CharStream<File>) -> Result<String, ParseError> {
+// The same rules `parse_identifier` holds names in a source to, for names that come from anywhere else
+pub(crate) fn is_valid_identifier(name: &str) -> bool {
+    return !name.is_empty() && name.chars().all(is_identifier_char) && (name != "proc") && (name != "region");
+}
+
+fn parse_identifier<R: Read>(stream: &mut CharStream<R>) -> Result<String, ParseError> {
     let mut identifier = String::new();
     loop {
         match stream.peek() {
@@ -155,7 +333,7 @@ fn parse_identifier(stream: &mut CharStream<File>) -> Result<String, ParseError>
     return Ok(identifier);
 }
 
-fn parse_number<T: FromStr>(stream: &mut CharStream<File>) -> Result<T, ParseError> {
+fn parse_number<T: FromStr, R: Read>(stream: &mut CharStream<R>) -> Result<T, ParseError> {
     let mut text = String::new();
     loop {
         match stream.peek() {
@@ -169,7 +347,15 @@ fn parse_number<T: FromStr>(stream: &mut CharStream<File>) -> Result<T, ParseErr
     return text.parse::<T>().map_err(|_| ParseError::MalformedNumber);
 }
 
-fn parse_region_reference(stream: &mut CharStream<File>) -> Result<RegionReference, ParseError> {
+fn parse_signed_number<R: Read>(stream: &mut CharStream<R>) -> Result<isize, ParseError> {
+    if stream.peek() == Some('-') {
+        stream.advance();
+        return isize::checked_neg(parse_number::<isize, R>(stream)?).ok_or(ParseError::MalformedNumber);
+    }
+    return parse_number::<isize, R>(stream);
+}
+
+fn parse_region_reference<R: Read>(stream: &mut CharStream<R>) -> Result<RegionReference, ParseError> {
     match stream.peek() {
         Some('$') => {
             stream.advance();
@@ -182,7 +368,41 @@ fn parse_region_reference(stream: &mut CharStream<File>) -> Result<RegionReferen
     }
 }
 
-fn parse_instruction(stream: &mut CharStream<File>) -> Result<ParsedInstruction, ParseError> {
+// Exactly two hex digits, upper or lower case
+fn parse_hex_byte<R: Read>(stream: &mut CharStream<R>) -> Result<u8, ParseError> {
+    let mut buf = String::new();
+    for _ in 0..2 {
+        match stream.next_char() {
+            Some(c) => buf.push(c),
+            None => return Err(ParseError::MalformedInstruction),
+        }
+    }
+    if !buf.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ParseError::MalformedInstruction);
+    }
+    return u8::from_str_radix(&buf, 16).map_err(|_| ParseError::MalformedInstruction);
+}
+
+// Whatever follows a backslash in a text literal. Hex escapes can produce any byte, not just valid UTF-8
+fn parse_escape<R: Read>(stream: &mut CharStream<R>) -> Option<u8> {
+    match stream.next_char()? {
+        'n' => return Some(b'\n'),
+        't' => return Some(b'\t'),
+        '\\' => return Some(b'\\'),
+        '\'' => return Some(b'\''),
+        '"' => return Some(b'"'),
+        'x' => {
+            let digits: String = [stream.next_char()?, stream.next_char()?].iter().collect();
+            if !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+                return None;
+            }
+            return u8::from_str_radix(&digits, 16).ok();
+        },
+        _ => return None,
+    }
+}
+
+fn parse_instruction<R: Read>(stream: &mut CharStream<R>) -> Result<ParsedInstruction, ParseError> {
     let instruction: char = stream.peek().ok_or(ParseError::MalformedInstruction)?;
     if !is_identifier_char(instruction) {
         stream.advance();
@@ -195,25 +415,123 @@ fn parse_instruction(stream: &mut CharStream<File>) -> Result<ParsedInstruction,
         '-' => return Ok(ParsedInstruction::Minus),
         '[' => return Ok(ParsedInstruction::LoopStart),
         ']' => return Ok(ParsedInstruction::LoopEnd),
+        '{' => return Ok(ParsedInstruction::ElseStart),
+        '}' => return Ok(ParsedInstruction::ElseEnd),
+        '?' => {
+            match stream.next_char() {
+                Some('>') => return Ok(ParsedInstruction::SeekZero(Direction::Right)),
+                Some('<') => return Ok(ParsedInstruction::SeekZero(Direction::Left)),
+                _ => return Err(ParseError::MalformedInstruction),
+            }
+        },
         ',' => return Ok(ParsedInstruction::Read),
+        '=' => return Ok(ParsedInstruction::Assert(parse_hex_byte(stream)?)),
         '.' => return Ok(ParsedInstruction::Write),
+        '/' => return Ok(ParsedInstruction::Newline),
         '"' => {
-            let mut buf = String::new();
-            for _ in 0..2 {
-                match stream.next() {
-                    Some(c) => buf.push(c),
-                    None => return Err(ParseError::MalformedInstruction),
+            if stream.peek() != Some('{') {
+                return Ok(ParsedInstruction::Quote(parse_hex_byte(stream)?));
+            }
+            stream.advance();
+            let mut bytes: Vec<u8> = Vec::new();
+            loop {
+                skip_whitespace(stream);
+                if stream.peek() == Some('}') {
+                    stream.advance();
+                    return Ok(ParsedInstruction::QuoteBytes(bytes));
                 }
+                bytes.push(parse_hex_byte(stream)?);
             }
-            if let Ok(value) = u8::from_str_radix(&buf, 16) {
-                return Ok(ParsedInstruction::Quote(value));
-            } else {
-                return Err(ParseError::MalformedInstruction);
+        },
+        '*' => {
+            // Intrinsics are the less common operations, which get a name instead of a sigil of their own
+            match parse_identifier(stream)?.as_str() {
+                "readline" => return Ok(ParsedInstruction::ReadLine),
+                "readuntil" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::ReadUntil(parse_hex_byte(stream)?));
+                },
+                "writeall" => return Ok(ParsedInstruction::WriteRegion),
+                "rotate" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Rotate(parse_signed_number(stream)?));
+                },
+                "goto" => return Ok(ParsedInstruction::GotoCell),
+                "cmp" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Compare(parse_region_reference(stream)?));
+                },
+                "not" => return Ok(ParsedInstruction::Not),
+                "and" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::And(parse_region_reference(stream)?));
+                },
+                "or" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Or(parse_region_reference(stream)?));
+                },
+                "xor" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Xor(parse_region_reference(stream)?));
+                },
+                "shl" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Shl(parse_number(stream)?));
+                },
+                "shr" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::Shr(parse_number(stream)?));
+                },
+                "mod" => {
+                    skip_whitespace(stream);
+                    let divisor: u8 = parse_number(stream)?;
+                    if divisor == 0 {
+                        return Err(ParseError::MalformedNumber);
+                    }
+                    return Ok(ParsedInstruction::Mod(divisor));
+                },
+                "copy" => {
+                    skip_whitespace(stream);
+                    return Ok(ParsedInstruction::CopyRel(parse_signed_number(stream)?));
+                },
+                "sum" => return Ok(ParsedInstruction::SumRegion),
+                "min" => return Ok(ParsedInstruction::MinRegion),
+                "max" => return Ok(ParsedInstruction::MaxRegion),
+                "info" => {
+                    skip_whitespace(stream);
+                    match parse_identifier(stream)?.as_str() {
+                        "id" => return Ok(ParsedInstruction::RuntimeInfo(InfoKind::Interpreter)),
+                        "width" => return Ok(ParsedInstruction::RuntimeInfo(InfoKind::CellWidth)),
+                        "regions" => return Ok(ParsedInstruction::RuntimeInfo(InfoKind::RegionCount)),
+                        _ => return Err(ParseError::MalformedInstruction),
+                    }
+                },
+                _ => return Err(ParseError::MalformedInstruction),
             }
         },
+        '\'' => {
+            let mut bytes: Vec<u8> = Vec::new();
+            loop {
+                let start: Position = stream.position();
+                match stream.next_char() {
+                    Some('\'') => break,
+                    Some('\\') => bytes.push(parse_escape(stream).ok_or(ParseError::InvalidEscape(start))?),
+                    Some(c) => bytes.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes()),
+                    None => return Err(ParseError::MalformedInstruction),
+                }
+            }
+            return Ok(ParsedInstruction::WriteLiteral(bytes));
+        },
         '^' => {
             skip_whitespace(stream);
-            return Ok(ParsedInstruction::Send(parse_region_reference(stream)?));
+            let source: RegionReference = parse_region_reference(stream)?;
+            skip_whitespace(stream);
+            if stream.peek() == Some(':') {
+                stream.advance();
+                skip_whitespace(stream);
+                return Ok(ParsedInstruction::Transfer(source, parse_region_reference(stream)?));
+            }
+            return Ok(ParsedInstruction::Send(source));
         },
         '&' => {
             skip_whitespace(stream);
@@ -221,15 +539,85 @@ fn parse_instruction(stream: &mut CharStream<File>) -> Result<ParsedInstruction,
         },
         _ => {
             let procedure: String = parse_identifier(stream)?;
-            skip_whitespace(stream);
-            match stream.peek() {
-                Some('@') => {
-                    stream.advance();
-                    return Ok(ParsedInstruction::Call(procedure, Some(parse_region_reference(stream)?)));
+            let argument: bool = parse_argument(stream);
+            return parse_call(stream, procedure, argument);
+        },
+    }
+}
+
+// Whether the `[` right ahead starts a size or a window rather than a loop, which takes a number after it. Without
+// one, `proc@region[-]` and `(...)[-]` are still a call followed by a loop
+fn bracket_holds_number<R: Read>(stream: &mut CharStream<R>) -> bool {
+    if stream.peek() != Some('[') {
+        return false;
+    }
+    let mut offset: usize = 1;
+    loop {
+        match stream.peek_nth(offset) {
+            Some(c) if c.is_whitespace() => offset += 1,
+            Some(c) => return c.is_ascii_digit(),
+            None => return false,
+        }
+    }
+}
+
+// Like a scratch size, the window has to follow the region directly so that `proc@region [-]` keeps its meaning
+fn parse_window<R: Read>(stream: &mut CharStream<R>) -> Result<Option<Range<usize>>, ParseError> {
+    if !bracket_holds_number(stream) {
+        return Ok(None);
+    }
+    stream.advance();
+    skip_whitespace(stream);
+    let start: usize = parse_number::<usize, R>(stream)?;
+    skip_whitespace(stream);
+    return parse_window_end(stream, start).map(Some);
+}
+
+// Everything from the colon on, once the start of the window has been read
+fn parse_window_end<R: Read>(stream: &mut CharStream<R>, start: usize) -> Result<Range<usize>, ParseError> {
+    expect_keyword(stream, ":")?;
+    skip_whitespace(stream);
+    let end: usize = parse_number::<usize, R>(stream)?;
+    skip_whitespace(stream);
+    expect_keyword(stream, "]")?;
+    if start >= end {
+        return Err(ParseError::WindowOutOfBounds);
+    }
+    return Ok(start..end);
+}
+
+// The `!` that passes the current cell to the callee has to follow the name or bracket directly
+fn parse_argument<R: Read>(stream: &mut CharStream<R>) -> bool {
+    if stream.peek() != Some('!') {
+        return false;
+    }
+    stream.advance();
+    return true;
+}
+
+fn parse_call<R: Read>(stream: &mut CharStream<R>, procedure: String, argument: bool) -> Result<ParsedInstruction, ParseError> {
+    skip_whitespace(stream);
+    match stream.peek() {
+        Some('@') => {
+            stream.advance();
+            let region: RegionReference = parse_region_reference(stream)?;
+            // `new` with a size makes a region for the call, while with a colon it is still a window on a region that
+            // happens to be called `new`
+            if let RegionReference::Named(name) = &region && (name == "new") && bracket_holds_number(stream) {
+                stream.advance();
+                skip_whitespace(stream);
+                let size: usize = parse_number::<usize, R>(stream)?;
+                skip_whitespace(stream);
+                if stream.peek() == Some(':') {
+                    return Ok(ParsedInstruction::Call(procedure, Some(region), Some(parse_window_end(stream, size)?), argument));
                 }
-                _ => return Ok(ParsedInstruction::Call(procedure, None)),
+                expect_keyword(stream, "]")?;
+                let size: NonZeroUsize = NonZeroUsize::new(size).ok_or(ParseError::MalformedNumber)?;
+                return Ok(ParsedInstruction::CallTransient(procedure, size, argument));
             }
-        },
+            return Ok(ParsedInstruction::Call(procedure, Some(region), parse_window(stream)?, argument));
+        }
+        _ => return Ok(ParsedInstruction::Call(procedure, None, None, argument)),
     }
 }
 
@@ -240,104 +628,370 @@ fn make_anonymous_name(base_name: &str, anonymous_count: usize) -> String {
     return name;
 }
 
-fn parse_instruction_list(stream: &mut CharStream<File>, name: &str) -> Result<Vec<(String, Vec<ParsedInstruction>)>, ParseError> {
+// Every procedure is lowered on its own, anonymous ones included, so a loop or else block has to close inside of the
+// same brackets it was opened in. The position is that of the bracket left without a partner
+fn check_brackets(instructions: &[ParsedInstruction], spans: &[Span]) -> Result<(), ParseError> {
+    let mut open: Vec<(&ParsedInstruction, Position)> = Vec::new();
+    for (instruction, span) in instructions.iter().zip(spans) {
+        match instruction {
+            ParsedInstruction::LoopStart | ParsedInstruction::ElseStart => open.push((instruction, span.start)),
+            ParsedInstruction::LoopEnd | ParsedInstruction::ElseEnd => {
+                let opening: Option<&ParsedInstruction> = open.pop().map(|(opening, _)| opening);
+                match (opening, instruction) {
+                    (Some(ParsedInstruction::LoopStart), ParsedInstruction::LoopEnd) => {},
+                    (Some(ParsedInstruction::ElseStart), ParsedInstruction::ElseEnd) => {},
+                    _ => return Err(ParseError::UnmatchedLoop(span.start)),
+                }
+            },
+            _ => {},
+        }
+    }
+    if let Some((_, position)) = open.pop() {
+        return Err(ParseError::UnmatchedLoop(position));
+    }
+    return Ok(());
+}
+
+// Stops at the terminator without consuming it. Anonymous procedures end at `)`, so a `;` inside one is an
+// error rather than the end of the enclosing declaration. The procedure being parsed comes last, after any
+// anonymous procedures declared inside of it. `depth` is how many more brackets can be opened inside of this one
+fn parse_instruction_list<R: Read>(stream: &mut CharStream<R>, options: &ParseOptions, name: &str, terminator: char, depth: usize, scratch_regions: &mut Vec<ParsedRegion>) -> Result<Vec<ParsedProcedure>, ParseError> {
     let mut anonymous_count: usize = 0;
-    let mut anonymous_procedures: Vec<(String, Vec<ParsedInstruction>)> = Vec::new();
+    let mut procedures: Vec<ParsedProcedure> = Vec::new();
     let mut instructions: Vec<ParsedInstruction> = Vec::new();
+    let mut spans: Vec<Span> = Vec::new();
     loop {
         skip_whitespace(stream);
+        let start: Position = stream.position();
         match stream.peek() {
-            Some(c) if is_instruction_char(c) => instructions.push(parse_instruction(stream)?),
+            // Procedure names can start with digits too, so a count is only a count when the bracket follows directly
+            Some(c) if c.is_ascii_digit() => {
+                let word: String = parse_identifier(stream)?;
+                match (word.parse::<usize>(), stream.peek()) {
+                    (Ok(count), Some('(')) => {
+                        if depth == 0 {
+                            return Err(ParseError::NestingTooDeep(stream.position()));
+                        }
+                        stream.advance();
+                        let mut body: Vec<ParsedProcedure> = parse_instruction_list(stream, options, &make_anonymous_name(name, anonymous_count), ')', depth - 1, scratch_regions)?;
+                        anonymous_count += 1;
+                        stream.advance();
+                        // Repeats are unrolled here, so the body is copied in rather than called
+                        let repeated: ParsedProcedure = body.pop().unwrap();
+                        let unrolled: Option<usize> = count.checked_mul(repeated.instructions.len()).and_then(|added| added.checked_add(instructions.len()));
+                        if unrolled.is_none_or(|unrolled| unrolled > options.max_unrolled_instructions) {
+                            return Err(ParseError::RepeatTooLarge(start));
+                        }
+                        procedures.append(&mut body);
+                        for _ in 0..count {
+                            instructions.extend(repeated.instructions.iter().cloned());
+                            spans.extend(repeated.spans.iter().copied());
+                        }
+                        continue;
+                    },
+                    _ => {
+                        let argument: bool = parse_argument(stream);
+                        instructions.push(parse_call(stream, word, argument)?);
+                    },
+                }
+            },
+            Some(c) if is_instruction_char(c) => {
+                let instruction: ParsedInstruction = parse_instruction(stream)?;
+                if matches!(instruction, ParsedInstruction::ElseStart) && !matches!(instructions.last(), Some(ParsedInstruction::LoopEnd)) {
+                    return Err(ParseError::MalformedInstruction);
+                }
+                instructions.push(instruction);
+            },
             Some('(') => {
+                if depth == 0 {
+                    return Err(ParseError::NestingTooDeep(start));
+                }
                 stream.advance();
                 let anonymous_name = make_anonymous_name(name, anonymous_count);
-                anonymous_procedures.append(&mut parse_instruction_list(stream, &anonymous_name)?);
+                procedures.append(&mut parse_instruction_list(stream, options, &anonymous_name, ')', depth - 1, scratch_regions)?);
                 anonymous_count += 1;
                 stream.advance();
-                skip_whitespace(stream);
-                match stream.peek() {
-                    Some('@') => {
-                        stream.advance();
-                        instructions.push(ParsedInstruction::Call(anonymous_name, Some(parse_region_reference(stream)?)));
-                    }
-                    _ => instructions.push(ParsedInstruction::Call(anonymous_name, None)),
+                let argument: bool = parse_argument(stream);
+                // A scratch size has to follow the bracket directly, otherwise `(...) [-]` would change meaning, and
+                // starts with a number, so that `(...)[-]` is still a loop. The region is named after the block, and
+                // the dash keeps it from clashing with anything declared in the source
+                if bracket_holds_number(stream) {
+                    stream.advance();
+                    skip_whitespace(stream);
+                    let size: NonZeroUsize = parse_number::<NonZeroUsize, R>(stream)?;
+                    skip_whitespace(stream);
+                    expect_keyword(stream, "]")?;
+                    let region_name: String = format!("{}-scratch", anonymous_name);
+                    scratch_regions.push(ParsedRegion { name: region_name.clone(), size, initial_bytes: None, readonly: false });
+                    instructions.push(ParsedInstruction::Call(anonymous_name, Some(RegionReference::Named(region_name.clone())), None, argument));
+                    instructions.push(ParsedInstruction::ResetScratch(region_name));
+                    let span: Span = Span { start, end: stream.position() };
+                    spans.extend([span, span]);
+                    continue;
                 }
+                instructions.push(parse_call(stream, anonymous_name, argument)?);
             },
-            Some(';') => break,
-            Some(c) if c != ')' => return Err(ParseError::MalformedProcedureDeclaration),
-            _ => break,
+            Some(c) if c == terminator => break,
+            _ => return Err(ParseError::MalformedProcedureDeclaration),
         }
+        spans.push(Span { start, end: stream.position() });
     }
-    anonymous_procedures.push((name.to_string(), instructions));
-    return Ok(anonymous_procedures);
+    check_brackets(&instructions, &spans)?;
+    procedures.push(ParsedProcedure { name: name.to_string(), is_anonymous: true, instructions, spans });
+    return Ok(procedures);
 }
 
-fn parse_region(stream: &mut CharStream<File>) -> Result<ParsedRegion, ParseError> {
+fn parse_region<R: Read>(stream: &mut CharStream<R>, options: &ParseOptions) -> Result<Vec<ParsedRegion>, ParseError> {
     expect_keyword(stream, "region")?;
+    let mut names: Vec<String> = Vec::new();
+    loop {
+        skip_whitespace(stream);
+        names.push(parse_identifier(stream)?);
+        skip_whitespace(stream);
+        if stream.peek() != Some(',') {
+            break;
+        }
+        stream.advance();
+    }
+    expect_keyword(stream, "[")?;
+    skip_whitespace(stream);
+    // Zero fails to parse just like anything else that isn't a valid size
+    let size: NonZeroUsize = parse_number::<NonZeroUsize, R>(stream)?;
+    if size.get() > options.max_region_size {
+        return Err(ParseError::RegionTooLarge { requested: size.get(), maximum: options.max_region_size });
+    }
+    expect_keyword(stream, "]")?;
+    skip_whitespace(stream);
+    let mut readonly: bool = false;
+    if stream.peek() == Some('r') {
+        expect_keyword(stream, "readonly")?;
+        readonly = true;
+        skip_whitespace(stream);
+    }
+    let mut initial_bytes: Option<Vec<u8>> = None;
+    if stream.peek() == Some('=') {
+        stream.advance();
+        let bytes: Vec<u8> = parse_initializer(stream)?;
+        if bytes.len() > size.get() {
+            return Err(ParseError::InitializerTooLong { length: bytes.len(), size: size.get() });
+        }
+        initial_bytes = Some(bytes);
+        skip_whitespace(stream);
+    }
+    expect_keyword(stream, ";")?;
+    return Ok(names.into_iter().map(|name| ParsedRegion { name, size, initial_bytes: initial_bytes.clone(), readonly }).collect());
+}
+
+// Decimal bytes in curly brackets, separated by commas, as in `{1, 2, 255}`
+fn parse_initializer<R: Read>(stream: &mut CharStream<R>) -> Result<Vec<u8>, ParseError> {
+    skip_whitespace(stream);
+    expect_keyword(stream, "{")?;
+    let mut bytes: Vec<u8> = Vec::new();
+    loop {
+        skip_whitespace(stream);
+        if stream.peek() == Some('}') {
+            stream.advance();
+            return Ok(bytes);
+        }
+        if !bytes.is_empty() {
+            expect_keyword(stream, ",")?;
+            skip_whitespace(stream);
+        }
+        bytes.push(parse_number::<u8, R>(stream)?);
+    }
+}
+
+fn parse_alias<R: Read>(stream: &mut CharStream<R>) -> Result<(String, String), ParseError> {
+    expect_keyword(stream, "alias")?;
     skip_whitespace(stream);
     let name: String = parse_identifier(stream)?;
     skip_whitespace(stream);
-    expect_keyword(stream, "[")?;
+    expect_keyword(stream, "=")?;
     skip_whitespace(stream);
-    // Again, I hate this. Sucks for me.
-    let size: NonZeroUsize = match NonZeroUsize::new(parse_number::<usize>(stream)?) {
-        Some(s) => s,
-        None => return Err(ParseError::MalformedNumber),
-    };
-    expect_keyword(stream, "]")?;
+    let region: String = parse_identifier(stream)?;
     skip_whitespace(stream);
     expect_keyword(stream, ";")?;
-    return Ok(ParsedRegion { name, size });
+    return Ok((name, region));
 }
 
-fn parse_procedure(stream: &mut CharStream<File>) -> Result<Vec<ParsedProcedure>, ParseError> {
-    let mut procedures: Vec<ParsedProcedure> = Vec::new();
+// Scratch regions declared by the procedure's anonymous blocks are collected separately, since they end up alongside
+// the declared regions
+fn parse_procedure<R: Read>(stream: &mut CharStream<R>, options: &ParseOptions, scratch_regions: &mut Vec<ParsedRegion>) -> Result<Vec<ParsedProcedure>, ParseError> {
     expect_keyword(stream, "proc")?;
     skip_whitespace(stream);
     let name: String = parse_identifier(stream)?;
     expect_keyword(stream, ":")?;
-    let all_procedures: Vec<(String, Vec<ParsedInstruction>)> = parse_instruction_list(stream, &name)?;
+    let mut procedures: Vec<ParsedProcedure> = parse_instruction_list(stream, options, &name, ';', options.max_nesting_depth, scratch_regions)?;
     expect_keyword(stream, ";")?;
-    for (name, instructions) in all_procedures.into_iter() {
-        procedures.push(ParsedProcedure { name, instructions, is_anonymous: true });
-    }
     // There is always at least one element
     procedures.last_mut().unwrap().is_anonymous = false;
     return Ok(procedures);
 }
 
-pub fn parse(source_path: &Path) -> Result<ParseResult, ParseError> {
-    let stream: &mut CharStream<File> = &mut CharStream::new(File::open(source_path).map_err(|_| ParseError::MissingFile)?);
+// The instructions of `extend proc <name>: ...;`, which go on the end of a procedure declared earlier. Anonymous
+// blocks inside of it are named after the extension, so they can't clash with the ones the procedure already has
+fn parse_extension<R: Read>(stream: &mut CharStream<R>, count: usize, options: &ParseOptions, scratch_regions: &mut Vec<ParsedRegion>) -> Result<(String, Vec<ParsedProcedure>), ParseError> {
+    expect_keyword(stream, "extend")?;
+    skip_whitespace(stream);
+    expect_keyword(stream, "proc")?;
+    skip_whitespace(stream);
+    let name: String = parse_identifier(stream)?;
+    expect_keyword(stream, ":")?;
+    let procedures: Vec<ParsedProcedure> = parse_instruction_list(stream, options, &format!("{}-extend-{}", name, count), ';', options.max_nesting_depth, scratch_regions)?;
+    expect_keyword(stream, ";")?;
+    return Ok((name, procedures));
+}
+
+fn allocate(allocated: &mut usize, size: NonZeroUsize, options: &ParseOptions) -> Result<(), ParseError> {
+    *allocated = usize::saturating_add(*allocated, size.get());
+    if *allocated > options.memory_budget {
+        return Err(ParseError::MemoryBudgetExceeded { requested: *allocated, budget: options.memory_budget });
+    }
+    return Ok(());
+}
+
+#[cfg(feature = "stdio")]
+pub fn parse(source_path: &Path, options: &ParseOptions) -> Result<ParseResult, ParseError> {
+    return parse_reader(File::open(source_path).map_err(|_| ParseError::MissingFile)?, options);
+}
+
+pub fn parse_str(source: &str, options: &ParseOptions) -> Result<ParseResult, ParseError> {
+    return parse_reader(source.as_bytes(), options);
+}
+
+// Everything before the first non-ASCII byte is ASCII, so each byte there is also exactly one column
+fn check_ascii(source: &[u8]) -> Result<(), ParseError> {
+    let Some(offset) = source.iter().position(|byte| !byte.is_ascii()) else {
+        return Ok(());
+    };
+    let line_start: usize = source[..offset].iter().rposition(|byte| *byte == b'\n').map_or(0, |newline| newline + 1);
+    return Err(ParseError::NonAsciiSource(Position {
+        offset,
+        line: source[..offset].iter().filter(|byte| **byte == b'\n').count() + 1,
+        column: offset - line_start + 1,
+    }));
+}
+
+pub fn parse_reader<R: Read>(mut source: R, options: &ParseOptions) -> Result<ParseResult, ParseError> {
+    if options.ascii_only {
+        let mut bytes: Vec<u8> = Vec::new();
+        while let Some(byte) = source.read_byte().map_err(ParseError::Io)? {
+            bytes.push(byte);
+        }
+        check_ascii(&bytes)?;
+        return parse_stream(&mut CharStream::new(bytes.as_slice()), options);
+    }
+    // Whatever the parser made of the source being cut short, the read or decoding error is what actually went wrong
+    let mut stream: CharStream<R> = CharStream::new(source);
+    let result: Result<ParseResult, ParseError> = parse_stream(&mut stream, options);
+    if let Some(error) = stream.take_error() {
+        return Err(error);
+    }
+    return result;
+}
+
+fn parse_stream<R: Read>(stream: &mut CharStream<R>, options: &ParseOptions) -> Result<ParseResult, ParseError> {
     let mut result: ParseResult = ParseResult::new();
+    let mut allocated: usize = 0;
+    let mut extensions: usize = 0;
 
     skip_whitespace(stream);
     while let Some(c) = stream.peek() {
         match c {
-            'r' => result.regions.push(parse_region(stream)?),
-            'p' => result.procedures.append(&mut parse_procedure(stream)?),
+            'r' => {
+                for region in parse_region(stream, options)? {
+                    allocate(&mut allocated, region.size, options)?;
+                    result.regions.push(region);
+                }
+            },
+            'p' | 'e' => {
+                let mut scratch_regions: Vec<ParsedRegion> = Vec::new();
+                if c == 'p' {
+                    result.procedures.append(&mut parse_procedure(stream, options, &mut scratch_regions)?);
+                } else {
+                    let (name, mut procedures): (String, Vec<ParsedProcedure>) = parse_extension(stream, extensions, options, &mut scratch_regions)?;
+                    extensions += 1;
+                    // There is always at least one element
+                    let body: ParsedProcedure = procedures.pop().unwrap();
+                    result.procedures.append(&mut procedures);
+                    let Some(procedure) = result.procedures.iter_mut().find(|procedure| !procedure.is_anonymous && (procedure.name == name)) else {
+                        return Err(ParseError::UndefinedReference);
+                    };
+                    procedure.instructions.extend(body.instructions);
+                    procedure.spans.extend(body.spans);
+                }
+                for region in scratch_regions {
+                    if region.size.get() > options.max_region_size {
+                        return Err(ParseError::RegionTooLarge { requested: region.size.get(), maximum: options.max_region_size });
+                    }
+                    allocate(&mut allocated, region.size, options)?;
+                    result.regions.push(region);
+                }
+            },
+            'a' => {
+                let (name, region): (String, String) = parse_alias(stream)?;
+                if result.aliases.insert(name, region).is_some() {
+                    return Err(ParseError::DuplicateIdentifier);
+                }
+            },
+            '#' if options.preserve_comments => result.comments.push(parse_comment(stream)),
             '#' => skip_comment(stream),
             _ => return Err(ParseError::MalformedLine),
         }
         skip_whitespace(stream);
     }
+    if !result.regions.iter().any(|region| region.name == "main") {
+        allocate(&mut allocated, options.default_region_size, options)?;
+        result.regions.push(ParsedRegion { name: "main".to_string(), size: options.default_region_size, initial_bytes: None, readonly: false });
+    }
 
     // Verify that all references are resolved before execution, to avoid runtime issues
-    let mut procedure_names: HashSet<&str> = HashSet::new();
-    let mut region_names: HashSet<&str> = HashSet::new();
+    let mut procedure_names: BTreeSet<&str> = BTreeSet::new();
+    let mut region_names: BTreeSet<&str> = BTreeSet::new();
     for procedure in &result.procedures {
         if !procedure_names.insert(&procedure.name) {
             return Err(ParseError::DuplicateIdentifier);
         }
     }
+    // Execution always starts there, which also catches sources that are nothing but comments and whitespace
+    if !procedure_names.contains("main") {
+        return Err(ParseError::MissingMain);
+    }
     for region in &result.regions {
         if !region_names.insert(&region.name) {
             return Err(ParseError::DuplicateIdentifier);
         }
     }
+    // An alias has to name a declared region rather than another alias, and can't shadow a region of its own
+    for (name, region) in &result.aliases {
+        if region_names.contains(name.as_str()) {
+            return Err(ParseError::DuplicateIdentifier);
+        }
+        if !region_names.contains(region.as_str()) {
+            return Err(ParseError::UndefinedReference);
+        }
+    }
+    // Windows on the back reference can only be checked once the region is known at runtime
+    let region_sizes: BTreeMap<&str, usize> = result.regions.iter().map(|region| (region.name.as_str(), region.size.get())).collect();
     for procedure in &result.procedures {
+        for instruction in &procedure.instructions {
+            match instruction {
+                ParsedInstruction::Call(_, Some(RegionReference::Named(region)), Some(window), _) => {
+                    let region: &str = result.aliases.get(region).unwrap_or(region);
+                    if region_sizes.get(region).is_some_and(|size| window.end > *size) {
+                        return Err(ParseError::WindowOutOfBounds);
+                    }
+                },
+                // Only the size of each one, since how many exist at once depends on how deep the calls go
+                ParsedInstruction::CallTransient(_, size, _) if size.get() > options.max_region_size => {
+                    return Err(ParseError::RegionTooLarge { requested: size.get(), maximum: options.max_region_size });
+                },
+                _ => {},
+            }
+        }
         for reference in procedure.get_all_references() {
             match reference {
-                ReferencedItem::Region(region) if region_names.contains(region) => {},
+                ReferencedItem::Region(region) if region_names.contains(region) || result.aliases.contains_key(region) => {},
                 ReferencedItem::Procedure(procedure) if procedure_names.contains(procedure) => {},
                 _ => return Err(ParseError::UndefinedReference),
             }
@@ -345,3 +999,221 @@ pub fn parse(source_path: &Path) -> Result<ParseResult, ParseError> {
     }
     return Ok(result);
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_procedure_named(source: &str, name: &str) -> Vec<ParsedInstruction> {
+        let result: ParseResult = parse_str(source, &ParseOptions::default()).unwrap();
+        return result.procedures.into_iter().find(|procedure| procedure.name == name).unwrap().instructions;
+    }
+
+    #[test]
+    fn bracket_without_number_after_region_is_a_loop() {
+        let instructions: Vec<ParsedInstruction> = parse_procedure_named("region r[4]; proc f: + ; proc main: \"41 f@r[-] . ;", "main");
+        assert!(matches!(&instructions[1], ParsedInstruction::Call(procedure, Some(RegionReference::Named(region)), None, false) if (procedure == "f") && (region == "r")));
+        assert!(matches!(&instructions[2..5], [ParsedInstruction::LoopStart, ParsedInstruction::Minus, ParsedInstruction::LoopEnd]));
+    }
+
+    #[test]
+    fn bracket_with_number_after_region_is_a_window() {
+        let instructions: Vec<ParsedInstruction> = parse_procedure_named("region r[8]; proc f: + ; proc main: f@r[2:6] f@r[ 0 : 1 ] ;", "main");
+        assert!(matches!(&instructions[0], ParsedInstruction::Call(_, _, Some(window), _) if *window == (2..6)));
+        assert!(matches!(&instructions[1], ParsedInstruction::Call(_, _, Some(window), _) if *window == (0..1)));
+        assert_eq!(instructions.len(), 2);
+    }
+
+    #[test]
+    fn bracket_without_number_after_block_is_a_loop() {
+        let instructions: Vec<ParsedInstruction> = parse_procedure_named("proc main: (+)[-] ;", "main");
+        assert!(matches!(&instructions[0], ParsedInstruction::Call(_, None, None, false)));
+        assert!(matches!(&instructions[1..], [ParsedInstruction::LoopStart, ParsedInstruction::Minus, ParsedInstruction::LoopEnd]));
+    }
+
+    #[test]
+    fn bracket_with_number_after_block_is_a_scratch_region() {
+        let result: ParseResult = parse_str("proc main: (+)[4] ;", &ParseOptions::default()).unwrap();
+        assert!(result.regions.iter().any(|region| (region.name == "main-anon-0-scratch") && (region.size.get() == 4)));
+        let main: &ParsedProcedure = result.procedures.iter().find(|procedure| procedure.name == "main").unwrap();
+        assert!(matches!(&main.instructions[..], [ParsedInstruction::Call(_, Some(_), None, false), ParsedInstruction::ResetScratch(_)]));
+    }
+
+    #[test]
+    fn repeats_past_the_unroll_limit_fail_to_parse() {
+        let options: ParseOptions = ParseOptions { max_unrolled_instructions: 8, ..ParseOptions::default() };
+        assert_eq!(parse_procedure_named("proc main: 4(+>) ;", "main").len(), 8);
+        assert!(parse_str("proc main: 4(+>) ;", &options).is_ok());
+        assert!(matches!(parse_str("proc main: + 4(+>) ;", &options), Err(ParseError::RepeatTooLarge(Position { line: 1, column: 14, .. }))));
+        assert!(matches!(parse_str("proc main: 1000000000(1000000000(+)) ;", &ParseOptions::default()), Err(ParseError::RepeatTooLarge(_))));
+    }
+
+    // Gives the source as far as it goes, then fails instead of running out
+    #[cfg(feature = "std")]
+    struct FailingReader(&'static [u8]);
+
+    #[cfg(feature = "std")]
+    impl std::io::Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.0.is_empty() {
+                return Err(std::io::Error::other("disconnected"));
+            }
+            return std::io::Read::read(&mut self.0, buf);
+        }
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn read_errors_are_reported_as_parse_errors() {
+        let ascii_only: ParseOptions = ParseOptions { ascii_only: true, ..ParseOptions::default() };
+        assert!(matches!(parse_reader(FailingReader(b"proc main: +"), &ascii_only), Err(ParseError::Io(_))));
+        assert!(matches!(parse_reader(FailingReader(b"proc main: +"), &ParseOptions::default()), Err(ParseError::Io(_))));
+    }
+
+    #[test]
+    fn invalid_utf8_is_reported_where_it_starts() {
+        let invalid: &[u8] = b"proc main: + ;\n# \xff\xff\xff\xff\xff\xff\n";
+        assert!(matches!(parse_reader(invalid, &ParseOptions::default()), Err(ParseError::InvalidUtf8(Position { offset: 17, line: 2, column: 3 }))));
+        // Cut short at the end of the source, which would otherwise read as if the source simply ended there
+        let truncated: &[u8] = b"proc main: + ;\n# \xe2\x82";
+        assert!(matches!(parse_reader(truncated, &ParseOptions::default()), Err(ParseError::InvalidUtf8(Position { offset: 17, line: 2, column: 3 }))));
+    }
+
+    #[test]
+    fn semicolon_inside_block_does_not_end_the_procedure() {
+        assert!(matches!(parse_str("proc main: (+;) - ;", &ParseOptions::default()), Err(ParseError::MalformedProcedureDeclaration)));
+        let instructions: Vec<ParsedInstruction> = parse_procedure_named("proc main: (+ (>)) - ;", "main");
+        assert!(matches!(&instructions[..], [ParsedInstruction::Call(_, None, None, false), ParsedInstruction::Minus]));
+    }
+
+    #[test]
+    fn zero_sized_regions_are_malformed_numbers() {
+        assert!(matches!(parse_str("region x[0]; proc main: ;", &ParseOptions::default()), Err(ParseError::MalformedNumber)));
+        assert!(matches!(parse_str("region x[a]; proc main: ;", &ParseOptions::default()), Err(ParseError::MalformedNumber)));
+        let result: ParseResult = parse_str("region x[1]; proc main: ;", &ParseOptions::default()).unwrap();
+        assert!(result.regions.iter().any(|region| (region.name == "x") && (region.size.get() == 1)));
+    }
+
+    #[test]
+    fn sources_without_main_fail_with_missing_main() {
+        assert!(matches!(parse_str(" \n\t\n", &ParseOptions::default()), Err(ParseError::MissingMain)));
+        assert!(matches!(parse_str("# nothing here\n# or here\n", &ParseOptions::default()), Err(ParseError::MissingMain)));
+        assert!(matches!(parse_str("region main[1]; proc other: + ;", &ParseOptions::default()), Err(ParseError::MissingMain)));
+    }
+
+    #[test]
+    fn crlf_sources_parse_like_unix_ones() {
+        let unix: &str = "# comment\nregion main[2];\n# another\nproc main:\n    +>'a\nb' ;\n";
+        let windows: String = unix.replace('\n', "\r\n");
+        let options: ParseOptions = ParseOptions { preserve_comments: true, ..ParseOptions::default() };
+        let unix: ParseResult = parse_str(unix, &options).unwrap();
+        let windows: ParseResult = parse_str(&windows, &options).unwrap();
+        assert_eq!(format!("{:?}", unix.procedures[0].instructions), format!("{:?}", windows.procedures[0].instructions));
+        assert_eq!(format!("{:?}", unix.regions), format!("{:?}", windows.regions));
+        let comments: Vec<&str> = windows.comments.iter().map(|comment| comment.text.as_str()).collect();
+        assert_eq!(comments, [" comment", " another"]);
+        assert!(matches!(&windows.procedures[0].instructions[2], ParsedInstruction::WriteLiteral(bytes) if bytes == b"a\nb"));
+        assert_eq!(windows.procedures[0].spans[2].start.line, 5);
+    }
+
+    #[test]
+    fn loops_cannot_cross_a_block_boundary() {
+        assert!(matches!(parse_str("proc main: [ ( ] ) ;", &ParseOptions::default()), Err(ParseError::UnmatchedLoop(Position { line: 1, column: 16, .. }))));
+        assert!(matches!(parse_str("proc main: ( [ ) ] ;", &ParseOptions::default()), Err(ParseError::UnmatchedLoop(Position { line: 1, column: 14, .. }))));
+        assert!(matches!(parse_str("proc main: [ ;", &ParseOptions::default()), Err(ParseError::UnmatchedLoop(Position { line: 1, column: 12, .. }))));
+        assert!(parse_str("proc main: [ ([-]) ] ;", &ParseOptions::default()).is_ok());
+    }
+
+    // Only within a single source, since there are no includes yet to extend a procedure from another file
+    #[test]
+    fn extensions_append_in_order() {
+        let source: &str = "proc main: + (>) ; extend proc main: - (<) ; extend proc main: ~ ;";
+        let result: ParseResult = parse_str(source, &ParseOptions::default()).unwrap();
+        let main: &ParsedProcedure = result.procedures.iter().find(|procedure| procedure.name == "main").unwrap();
+        let calls: Vec<&str> = main.instructions.iter().filter_map(|instruction| match instruction {
+            ParsedInstruction::Call(procedure, _, _, _) => Some(procedure.as_str()),
+            _ => None,
+        }).collect();
+        assert_eq!(calls, ["main-anon-0", "main-extend-0-anon-0"]);
+        assert!(matches!(&main.instructions[..], [ParsedInstruction::Plus, _, ParsedInstruction::Minus, _, ParsedInstruction::Reset]));
+        assert_eq!(main.spans.len(), main.instructions.len());
+        assert!(matches!(parse_str("extend proc main: + ; proc main: ;", &ParseOptions::default()), Err(ParseError::UndefinedReference)));
+        assert!(matches!(parse_str("proc main: [ ; extend proc main: ] ;", &ParseOptions::default()), Err(ParseError::UnmatchedLoop(_))));
+    }
+
+    #[test]
+    fn deep_nesting_fails_cleanly() {
+        let nested = |depth: usize| format!("proc main: {}+{} ;", "(".repeat(depth), ")".repeat(depth));
+        assert!(parse_str(&nested(256), &ParseOptions::default()).is_ok());
+        assert!(matches!(parse_str(&nested(257), &ParseOptions::default()), Err(ParseError::NestingTooDeep(Position { column: 268, .. }))));
+        assert!(matches!(parse_str(&nested(100000), &ParseOptions::default()), Err(ParseError::NestingTooDeep(_))));
+        let repeated: String = format!("proc main: {}+{} ;", "1(".repeat(300), ")".repeat(300));
+        assert!(matches!(parse_str(&repeated, &ParseOptions::default()), Err(ParseError::NestingTooDeep(_))));
+        let shallow: ParseOptions = ParseOptions { max_nesting_depth: 2, ..ParseOptions::default() };
+        assert!(parse_str(&nested(2), &shallow).is_ok());
+        assert!(matches!(parse_str(&nested(3), &shallow), Err(ParseError::NestingTooDeep(_))));
+    }
+
+    #[test]
+    fn initializers_longer_than_the_region_fail() {
+        assert!(matches!(parse_str("region table[2] = {1, 2, 3}; proc main: ;", &ParseOptions::default()), Err(ParseError::InitializerTooLong { length: 3, size: 2 })));
+        let result: ParseResult = parse_str("region table[4] readonly = {1, 2}; proc main: ;", &ParseOptions::default()).unwrap();
+        let table: &ParsedRegion = result.regions.iter().find(|region| region.name == "table").unwrap();
+        assert_eq!(table.initial_bytes.as_deref(), Some(&[1, 2][..]));
+        assert!(table.readonly);
+    }
+
+    #[test]
+    fn windows_are_checked_against_named_regions() {
+        let options: &ParseOptions = &ParseOptions::default();
+        assert!(matches!(parse_str("region r[4]; proc f: ; proc main: f@r[2:5] ;", options), Err(ParseError::WindowOutOfBounds)));
+        assert!(matches!(parse_str("region r[4]; proc f: ; proc main: f@r[3:3] ;", options), Err(ParseError::WindowOutOfBounds)));
+        assert!(matches!(parse_str("region r[4]; alias a = r; proc f: ; proc main: f@a[0:5] ;", options), Err(ParseError::WindowOutOfBounds)));
+        assert!(parse_str("region r[4]; proc f: ; proc main: f@r[0:4] ;", options).is_ok());
+        // The back reference is only known at runtime
+        assert!(parse_str("region r[4]; proc f: ; proc main: f@$[0:50] ;", options).is_ok());
+    }
+
+    #[test]
+    fn json_ast_matches_the_version_1_schema() {
+        let options: ParseOptions = ParseOptions { preserve_comments: true, ..ParseOptions::default() };
+        let result: ParseResult = parse_str("# note\nregion main[2] = {7};\nproc main: + (^$)@main ;", &options).unwrap();
+        assert_eq!(
+            crate::parser::json::to_json(&result),
+            "{\"version\":1,\"regions\":[{\"name\":\"main\",\"size\":2,\"readonly\":false,\"initializer\":[7]}],\"aliases\":{},\"procedures\":[{\"name\":\"main-anon-0\",\"anonymous\":true,\"instructions\":[{\"op\":\"send\",\"region\":{\"kind\":\"back\"}}],\"spans\":[{\"start\":{\"line\":3,\"column\":15},\"end\":{\"line\":3,\"column\":17}}]},{\"name\":\"main\",\"anonymous\":false,\"instructions\":[{\"op\":\"plus\"},{\"op\":\"call\",\"procedure\":\"main-anon-0\",\"region\":{\"kind\":\"named\",\"name\":\"main\"},\"window\":null,\"argument\":false}],\"spans\":[{\"start\":{\"line\":3,\"column\":12},\"end\":{\"line\":3,\"column\":13}},{\"start\":{\"line\":3,\"column\":14},\"end\":{\"line\":3,\"column\":23}}]}],\"comments\":[{\"text\":\" note\",\"span\":{\"start\":{\"line\":1,\"column\":1},\"end\":{\"line\":1,\"column\":7}}}]}",
+        );
+    }
+
+    #[test]
+    fn regions_past_the_maximum_size_fail_to_parse() {
+        let options: ParseOptions = ParseOptions { max_region_size: 8, ..ParseOptions::default() };
+        assert!(parse_str("region main[8]; proc main: (+)[8] f@new[8] ; proc f: ;", &options).is_ok());
+        assert!(matches!(parse_str("region main[9]; proc main: ;", &options), Err(ParseError::RegionTooLarge { requested: 9, maximum: 8 })));
+        assert!(matches!(parse_str("region main[1]; proc main: (+)[10] ;", &options), Err(ParseError::RegionTooLarge { requested: 10, maximum: 8 })));
+        assert!(matches!(parse_str("region main[1]; proc main: f@new[11] ; proc f: ;", &options), Err(ParseError::RegionTooLarge { requested: 11, maximum: 8 })));
+    }
+
+    #[test]
+    fn regions_past_the_memory_budget_fail_to_parse() {
+        let options: ParseOptions = ParseOptions { memory_budget: 16, default_region_size: NonZeroUsize::new(6).unwrap(), ..ParseOptions::default() };
+        // Exactly the budget, counting the scratch region and the main region added by default
+        assert!(parse_str("region a[5]; proc main: (+)[5] ;", &options).is_ok());
+        assert!(matches!(parse_str("region a[5]; region b[5]; proc main: (+)[5] ;", &options), Err(ParseError::MemoryBudgetExceeded { requested: 21, budget: 16 })));
+        assert!(matches!(parse_str("region a, b[5]; region main[7]; proc main: ;", &options), Err(ParseError::MemoryBudgetExceeded { requested: 17, budget: 16 })));
+    }
+
+    #[test]
+    fn several_regions_can_share_a_declaration() {
+        let result: ParseResult = parse_str("region a, b, c[8] readonly = {1, 2}; proc main: ;", &ParseOptions::default()).unwrap();
+        let names: Vec<&str> = result.regions.iter().map(|region| region.name.as_str()).collect();
+        assert_eq!(names, ["a", "b", "c", "main"]);
+        for region in &result.regions[..3] {
+            assert_eq!(region.size.get(), 8);
+            assert_eq!(region.initial_bytes.as_deref(), Some(&[1, 2][..]));
+            assert!(region.readonly);
+        }
+        assert!(matches!(parse_str("region a, a[2]; proc main: ;", &ParseOptions::default()), Err(ParseError::DuplicateIdentifier)));
+        let single: ParseResult = parse_str("region a[3]; proc main: ;", &ParseOptions::default()).unwrap();
+        assert!(matches!(&single.regions[..], [ParsedRegion { name, size, initial_bytes: None, readonly: false }, _] if (name == "a") && (size.get() == 3)));
+    }
+}
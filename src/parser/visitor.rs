@@ -0,0 +1,118 @@
+use alloc::collections::BTreeMap;
+use core::{num::NonZeroUsize, ops::Range};
+
+use crate::{parser::parser::{ParseResult, ParsedInstruction, ParsedProcedure}, procedure::{InfoKind, RegionReference}, region::Direction};
+
+// One method per instruction, each doing nothing by default, so a visitor only has to implement what it cares about
+pub trait Visitor {
+    fn visit_right(&mut self) -> () {}
+    fn visit_left(&mut self) -> () {}
+    fn visit_reset(&mut self) -> () {}
+    fn visit_plus(&mut self) -> () {}
+    fn visit_minus(&mut self) -> () {}
+    fn visit_loop_start(&mut self) -> () {}
+    fn visit_loop_end(&mut self) -> () {}
+    fn visit_else_start(&mut self) -> () {}
+    fn visit_else_end(&mut self) -> () {}
+    fn visit_seek_zero(&mut self, _direction: Direction) -> () {}
+    fn visit_rotate(&mut self, _amount: isize) -> () {}
+    fn visit_goto_cell(&mut self) -> () {}
+    fn visit_read(&mut self) -> () {}
+    fn visit_read_line(&mut self) -> () {}
+    fn visit_read_until(&mut self, _delimiter: u8) -> () {}
+    fn visit_write(&mut self) -> () {}
+    fn visit_write_region(&mut self) -> () {}
+    fn visit_newline(&mut self) -> () {}
+    fn visit_write_literal(&mut self, _bytes: &[u8]) -> () {}
+    fn visit_quote(&mut self, _value: u8) -> () {}
+    fn visit_quote_bytes(&mut self, _bytes: &[u8]) -> () {}
+    fn visit_assert(&mut self, _expected: u8) -> () {}
+    fn visit_send(&mut self, _region: &RegionReference) -> () {}
+    fn visit_receive(&mut self, _region: &RegionReference) -> () {}
+    fn visit_transfer(&mut self, _source: &RegionReference, _destination: &RegionReference) -> () {}
+    fn visit_compare(&mut self, _region: &RegionReference) -> () {}
+    fn visit_not(&mut self) -> () {}
+    fn visit_and(&mut self, _region: &RegionReference) -> () {}
+    fn visit_or(&mut self, _region: &RegionReference) -> () {}
+    fn visit_xor(&mut self, _region: &RegionReference) -> () {}
+    fn visit_shl(&mut self, _amount: u8) -> () {}
+    fn visit_shr(&mut self, _amount: u8) -> () {}
+    fn visit_mod(&mut self, _divisor: u8) -> () {}
+    fn visit_copy_relative(&mut self, _offset: isize) -> () {}
+    fn visit_sum(&mut self) -> () {}
+    fn visit_min(&mut self) -> () {}
+    fn visit_max(&mut self) -> () {}
+    fn visit_runtime_info(&mut self, _kind: InfoKind) -> () {}
+    // Only for calls to named procedures, anonymous ones are walked in place between the two methods below
+    fn visit_call(&mut self, _procedure: &str, _region: Option<&RegionReference>, _window: Option<&Range<usize>>, _argument: bool) -> () {}
+    fn enter_anonymous(&mut self, _procedure: &ParsedProcedure, _region: Option<&RegionReference>, _window: Option<&Range<usize>>, _argument: bool) -> () {}
+    fn leave_anonymous(&mut self, _procedure: &ParsedProcedure) -> () {}
+    // For anonymous procedures as well, which aren't walked in place here since they share nothing with the code
+    // around them
+    fn visit_call_transient(&mut self, _procedure: &str, _size: NonZeroUsize, _argument: bool) -> () {}
+    fn visit_reset_scratch(&mut self, _region: &str) -> () {}
+}
+
+fn walk_instructions<V: Visitor>(procedures: &BTreeMap<&str, &ParsedProcedure>, procedure: &ParsedProcedure, visitor: &mut V) -> () {
+    for instruction in &procedure.instructions {
+        match instruction {
+            ParsedInstruction::Right => visitor.visit_right(),
+            ParsedInstruction::Left => visitor.visit_left(),
+            ParsedInstruction::Reset => visitor.visit_reset(),
+            ParsedInstruction::Plus => visitor.visit_plus(),
+            ParsedInstruction::Minus => visitor.visit_minus(),
+            ParsedInstruction::LoopStart => visitor.visit_loop_start(),
+            ParsedInstruction::LoopEnd => visitor.visit_loop_end(),
+            ParsedInstruction::ElseStart => visitor.visit_else_start(),
+            ParsedInstruction::ElseEnd => visitor.visit_else_end(),
+            ParsedInstruction::SeekZero(direction) => visitor.visit_seek_zero(*direction),
+            ParsedInstruction::Rotate(amount) => visitor.visit_rotate(*amount),
+            ParsedInstruction::GotoCell => visitor.visit_goto_cell(),
+            ParsedInstruction::Read => visitor.visit_read(),
+            ParsedInstruction::ReadLine => visitor.visit_read_line(),
+            ParsedInstruction::ReadUntil(delimiter) => visitor.visit_read_until(*delimiter),
+            ParsedInstruction::Write => visitor.visit_write(),
+            ParsedInstruction::WriteRegion => visitor.visit_write_region(),
+            ParsedInstruction::Newline => visitor.visit_newline(),
+            ParsedInstruction::WriteLiteral(bytes) => visitor.visit_write_literal(bytes),
+            ParsedInstruction::Quote(value) => visitor.visit_quote(*value),
+            ParsedInstruction::QuoteBytes(bytes) => visitor.visit_quote_bytes(bytes),
+            ParsedInstruction::Assert(expected) => visitor.visit_assert(*expected),
+            ParsedInstruction::Send(region) => visitor.visit_send(region),
+            ParsedInstruction::Receive(region) => visitor.visit_receive(region),
+            ParsedInstruction::Transfer(source, destination) => visitor.visit_transfer(source, destination),
+            ParsedInstruction::Compare(region) => visitor.visit_compare(region),
+            ParsedInstruction::Not => visitor.visit_not(),
+            ParsedInstruction::And(region) => visitor.visit_and(region),
+            ParsedInstruction::Or(region) => visitor.visit_or(region),
+            ParsedInstruction::Xor(region) => visitor.visit_xor(region),
+            ParsedInstruction::Shl(amount) => visitor.visit_shl(*amount),
+            ParsedInstruction::Shr(amount) => visitor.visit_shr(*amount),
+            ParsedInstruction::Mod(divisor) => visitor.visit_mod(*divisor),
+            ParsedInstruction::CopyRel(offset) => visitor.visit_copy_relative(*offset),
+            ParsedInstruction::SumRegion => visitor.visit_sum(),
+            ParsedInstruction::MinRegion => visitor.visit_min(),
+            ParsedInstruction::MaxRegion => visitor.visit_max(),
+            ParsedInstruction::RuntimeInfo(kind) => visitor.visit_runtime_info(*kind),
+            ParsedInstruction::ResetScratch(region) => visitor.visit_reset_scratch(region),
+            ParsedInstruction::Call(name, region, window, argument) => {
+                match procedures.get(name.as_str()) {
+                    Some(callee) if callee.is_anonymous => {
+                        visitor.enter_anonymous(callee, region.as_ref(), window.as_ref(), *argument);
+                        walk_instructions(procedures, callee, visitor);
+                        visitor.leave_anonymous(callee);
+                    },
+                    _ => visitor.visit_call(name, region.as_ref(), window.as_ref(), *argument),
+                }
+            },
+            ParsedInstruction::CallTransient(name, size, argument) => visitor.visit_call_transient(name, *size, *argument),
+        }
+    }
+}
+
+// Anonymous procedures are stored flattened alongside the named ones, so the whole result is needed to find the
+// bodies to descend into
+pub fn walk<V: Visitor>(result: &ParseResult, procedure: &ParsedProcedure, visitor: &mut V) -> () {
+    let procedures: BTreeMap<&str, &ParsedProcedure> = result.procedures.iter().map(|procedure| (procedure.name.as_str(), procedure)).collect();
+    walk_instructions(&procedures, procedure, visitor);
+}
@@ -1,6 +1,9 @@
-use std::{cell::RefCell, collections::HashMap, io::{self, Read, Write}};
+use alloc::{collections::BTreeMap, format, string::{String, ToString}, vec::Vec};
+use core::{cell::RefCell, cmp::Ordering, num::NonZeroUsize, ops::Range, sync::atomic::Ordering as AtomicOrdering};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
-use crate::{parser::parser::ParsedInstruction, interpreter::program::Call, region::Region};
+use crate::{parser::{char_stream::Span, parser::ParsedInstruction}, interpreter::program::{ArithmeticMode, Call, EofMode, ExecutionContext, Exit, OutputBuffering, OutputEncoding, PointerMode, RuntimeError, TraceEntry}, region::{Direction, Region}};
 
 #[derive(Debug, Clone)]
 pub enum RegionReference {
@@ -8,6 +11,19 @@ pub enum RegionReference {
     Named(String),
 }
 
+// What `*info` stores in the current cell
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InfoKind {
+    // A byte that is the same for every version of this interpreter, so that a program can tell it apart from others
+    Interpreter,
+    // In bits, which is always 8 here
+    CellWidth,
+    // Scratch regions included, and capped at 255
+    RegionCount,
+}
+
+pub const INTERPRETER_ID: u8 = 0xCA;
+
 #[derive(Debug)]
 pub enum Instruction {
     Right,
@@ -15,14 +31,76 @@ pub enum Instruction {
     Reset,
     Plus,
     Minus,
+    // Jumps to its `LoopEnd` when the cell is zero, or straight to the `Else` of a while/else loop
     LoopStart(usize),
     LoopEnd(usize),
+    // Only reached in order once the loop before it has run, so it always jumps to its `EndElse`, while a jump here
+    // from the `LoopStart` runs the block instead
+    Else(usize),
+    EndElse,
+    SeekZero(Direction),
+    Rotate(isize),
+    GotoCell,
     Read,
+    ReadLine,
+    ReadUntil(u8),
     Write,
+    WriteRegion,
+    WriteByte(u8),
+    WriteLiteral(Vec<u8>),
     Quote(u8),
+    QuoteBytes(Vec<u8>),
+    Assert(u8),
     Send(RegionReference),
     Receive(RegionReference),
-    Call(String, Option<RegionReference>),
+    Transfer(RegionReference, RegionReference),
+    Compare(RegionReference),
+    Not,
+    And(RegionReference),
+    Or(RegionReference),
+    Xor(RegionReference),
+    Shl(u8),
+    Shr(u8),
+    Mod(u8),
+    CopyRel(isize),
+    SumRegion,
+    MinRegion,
+    MaxRegion,
+    RuntimeInfo(InfoKind),
+    Call(String, Option<RegionReference>, Option<Range<usize>>, bool),
+    CallTransient(String, NonZeroUsize, bool),
+    ResetScratch(String),
+}
+
+impl Instruction {
+    // Whether running the instruction changes the region it runs on. Moving the head doesn't count, but rotating the
+    // cells under it does
+    pub fn writes_region(&self) -> bool {
+        return matches!(self,
+            Instruction::Plus |
+            Instruction::Minus |
+            Instruction::Rotate(_) |
+            Instruction::Read |
+            Instruction::ReadLine |
+            Instruction::ReadUntil(_) |
+            Instruction::Quote(_) |
+            Instruction::QuoteBytes(_) |
+            Instruction::Receive(_) |
+            Instruction::Compare(_) |
+            Instruction::Not |
+            Instruction::And(_) |
+            Instruction::Or(_) |
+            Instruction::Xor(_) |
+            Instruction::Shl(_) |
+            Instruction::Shr(_) |
+            Instruction::Mod(_) |
+            Instruction::CopyRel(_) |
+            Instruction::SumRegion |
+            Instruction::MinRegion |
+            Instruction::MaxRegion |
+            Instruction::RuntimeInfo(_)
+        );
+    }
 }
 
 #[derive(Debug)]
@@ -30,6 +108,7 @@ pub struct Procedure {
     pub name: String,
     pub is_anonymous: bool,
     instructions: Vec<Instruction>,
+    spans: Vec<Span>,
 }
 
 fn find_forwards(instructions: &[ParsedInstruction], starting_point: usize) -> usize {
@@ -62,9 +141,195 @@ fn find_backwards(instructions: &[ParsedInstruction], starting_point: usize) ->
     panic!("No match found");
 }
 
+fn find_else_end(instructions: &[ParsedInstruction], starting_point: usize) -> usize {
+    let mut total: i128 = 0;
+    for (i, instruction) in instructions.iter().enumerate().skip(starting_point) {
+        match instruction {
+            ParsedInstruction::ElseStart => total += 1,
+            ParsedInstruction::ElseEnd => total -= 1,
+            _ => {},
+        }
+        if total == 0 {
+            return i;
+        }
+    }
+    panic!("No match found");
+}
+
+fn resolve_reference<'a>(reference: &'a RegionReference, back_reference: &'a str) -> &'a str {
+    match reference {
+        RegionReference::BackReference => return back_reference,
+        RegionReference::Named(region_name) => return region_name,
+    }
+}
+
+fn out_of_bounds(region: &Region) -> RuntimeError {
+    return RuntimeError::PointerOutOfBounds { region: region.name.to_string(), pointer: region.pointer() };
+}
+
+fn unexpected_eof(region: &Region) -> RuntimeError {
+    return RuntimeError::UnexpectedEof { region: region.name.to_string(), pointer: region.pointer() };
+}
+
+fn read_only(region: &Region) -> RuntimeError {
+    return RuntimeError::WriteToReadOnly { region: region.name.to_string(), pointer: region.pointer() };
+}
+
+// References are checked at parse time, but renaming a region at runtime can still leave one dangling
+pub fn find_region<'a>(regions: &'a BTreeMap<String, RefCell<Region>>, name: &str) -> Result<&'a RefCell<Region>, RuntimeError> {
+    return regions.get(name).ok_or_else(|| RuntimeError::UnknownRegion(name.to_string()));
+}
+
+// Transient regions aren't in the map, so the back reference is the only way to name one from another region
+fn find_other<'a>(context: &ExecutionContext<'a>, name: &str) -> Result<&'a RefCell<Region>, RuntimeError> {
+    if let Some(transient) = context.back_transient && (name == context.back_reference) {
+        return Ok(transient);
+    }
+    return find_region(context.regions, name);
+}
+
+// Send and receive quietly do nothing when the other region is already in use, this at least makes it observable.
+// There is nowhere to log to without `std`, so there the conflicts are only counted
+#[cfg_attr(not(feature = "std"), allow(unused_variables))]
+fn record_conflict(context: &mut ExecutionContext, region: &str, other: &str) -> () {
+    context.metrics.borrow_conflicts += 1;
+    #[cfg(feature = "std")]
+    if context.log_borrow_conflicts {
+        eprintln!("borrow conflict: {} could not access {}", region, other);
+    }
+}
+
+// Echoed bytes go straight to the output rather than through `write_output`, since they aren't the program's own
+fn read_byte(context: &mut ExecutionContext) -> Result<Option<u8>, RuntimeError> {
+    let value: Option<u8> = context.input.read_byte().map_err(RuntimeError::Io)?;
+    if let Some(byte) = value && context.echo_input {
+        context.output.write_all(&[byte]).map_err(RuntimeError::Io)?;
+    }
+    return Ok(value);
+}
+
+// Every write instruction goes through here so the output cap covers all of them. Whatever still fits under the
+// cap is written before stopping, so the output is never cut short by more than it has to be
+fn write_output(context: &mut ExecutionContext, bytes: &[u8]) -> Result<(), RuntimeError> {
+    let mut length: usize = bytes.len();
+    if let Some(limit) = context.limits.max_output {
+        let remaining: u64 = limit.saturating_sub(context.metrics.bytes_written);
+        length = usize::try_from(remaining).map_or(length, |remaining| remaining.min(length));
+    }
+    context.output.write_all(&bytes[..length]).map_err(RuntimeError::Io)?;
+    context.metrics.bytes_written += length as u64;
+    let flush: bool = match context.output_buffering {
+        OutputBuffering::None => true,
+        OutputBuffering::Line => bytes[..length].contains(&b'\n'),
+        OutputBuffering::Full => false,
+    };
+    if flush {
+        context.output.flush().map_err(RuntimeError::Io)?;
+    }
+    if length < bytes.len() {
+        return Err(RuntimeError::OutputLimitExceeded(context.limits.max_output.unwrap()));
+    }
+    return Ok(());
+}
+
+// Reading the clock for every instruction would slow everything down, so the deadline is only checked now and then
+fn check_limits(context: &ExecutionContext) -> Result<(), RuntimeError> {
+    let executed: u64 = context.metrics.instructions_executed;
+    if context.limits.max_instructions.is_some_and(|limit| executed > limit) {
+        return Err(RuntimeError::InstructionLimitExceeded(context.limits.max_instructions.unwrap()));
+    }
+    #[cfg(feature = "std")]
+    if executed.is_multiple_of(4096) && context.deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+        return Err(RuntimeError::TimeLimitExceeded(context.limits.time_limit.unwrap()));
+    }
+    return Ok(());
+}
+
+fn move_right(region: &mut Region, pointer_mode: PointerMode) -> Result<(), RuntimeError> {
+    if pointer_mode == PointerMode::Strict {
+        return region.checked_right().ok_or_else(|| out_of_bounds(region));
+    }
+    region.right();
+    return Ok(());
+}
+
+// Stores the bytes in the cells after the head and the number stored under it, leaving the head where it started.
+// Input that ends before the delimiter is stored all the same, so it is only an error when nothing is left at all
+fn read_until(region: &mut Region, context: &mut ExecutionContext, delimiter: u8, keep_delimiter: bool) -> Result<(), RuntimeError> {
+    let start: usize = region.pointer();
+    let mut length: u8 = 0;
+    let mut next: Option<u8> = read_byte(context)?;
+    if next.is_none() && (context.eof_mode == EofMode::Error) {
+        return Err(unexpected_eof(region));
+    }
+    while let Some(value) = next {
+        if (value == delimiter) && !keep_delimiter {
+            break;
+        }
+        move_right(region, context.pointer_mode)?;
+        region.set(value);
+        length = u8::wrapping_add(length, 1);
+        if value == delimiter {
+            break;
+        }
+        next = read_byte(context)?;
+    }
+    region.goto(start);
+    region.set(length);
+    return Ok(());
+}
+
+fn check_initialized(region: &Region) -> Result<(), RuntimeError> {
+    if !region.is_initialized() {
+        return Err(RuntimeError::UninitializedRead { region: region.name.to_string(), pointer: region.pointer() });
+    }
+    return Ok(());
+}
+
+fn check_all_initialized(region: &Region) -> Result<(), RuntimeError> {
+    if let Some(pointer) = region.first_uninitialized() {
+        return Err(RuntimeError::UninitializedRead { region: region.name.to_string(), pointer });
+    }
+    return Ok(());
+}
+
+fn call_argument(region: &Region, argument: bool) -> Result<Option<u8>, RuntimeError> {
+    if !argument {
+        return Ok(None);
+    }
+    check_initialized(region)?;
+    return Ok(Some(region.get()));
+}
+
+// The current cell of another region, for the instructions that combine it with the current one. A cell always
+// equals itself, and the current region can't be borrowed a second time anyway
+fn other_cell(region: &Region, reference: &RegionReference, context: &mut ExecutionContext) -> Result<u8, RuntimeError> {
+    let region_name: &str = resolve_reference(reference, context.back_reference);
+    if region_name == region.name {
+        return Ok(region.get());
+    }
+    let Ok(other) = find_other(context, region_name)?.try_borrow() else {
+        record_conflict(context, &region.name, region_name);
+        return Err(RuntimeError::RegionConflict(region_name.to_string()));
+    };
+    check_initialized(&other)?;
+    return Ok(other.get());
+}
+
+// Sizes are allowed to differ between regions, but with the check enabled a transfer is only valid if each pointer
+// would also be in bounds for the other region
+fn check_alignment(first: &Region, second: &Region) -> Result<(), RuntimeError> {
+    if first.pointer() >= second.len() {
+        return Err(RuntimeError::MisalignedTransfer { region: first.name.to_string(), pointer: first.pointer(), other: second.name.to_string() });
+    }
+    if second.pointer() >= first.len() {
+        return Err(RuntimeError::MisalignedTransfer { region: second.name.to_string(), pointer: second.pointer(), other: first.name.to_string() });
+    }
+    return Ok(());
+}
 
 impl Procedure {
-    pub fn new(name: &str, parsed_instructions: Vec<ParsedInstruction>, is_anonymous: bool) -> Procedure {
+    pub fn new(name: &str, parsed_instructions: Vec<ParsedInstruction>, spans: Vec<Span>, is_anonymous: bool) -> Procedure {
         let mut instructions: Vec<Instruction> = Vec::new();
         for (i, instruction) in parsed_instructions.iter().enumerate() {
             match instruction {
@@ -73,103 +338,758 @@ impl Procedure {
                 ParsedInstruction::Reset => instructions.push(Instruction::Reset),
                 ParsedInstruction::Plus => instructions.push(Instruction::Plus),
                 ParsedInstruction::Minus => instructions.push(Instruction::Minus),
-                ParsedInstruction::LoopStart => instructions.push(Instruction::LoopStart(find_forwards(&parsed_instructions, i))),
+                ParsedInstruction::LoopStart => {
+                    let end: usize = find_forwards(&parsed_instructions, i);
+                    if let Some(ParsedInstruction::ElseStart) = parsed_instructions.get(end + 1) {
+                        instructions.push(Instruction::LoopStart(end + 1));
+                    } else {
+                        instructions.push(Instruction::LoopStart(end));
+                    }
+                },
                 ParsedInstruction::LoopEnd => instructions.push(Instruction::LoopEnd(find_backwards(&parsed_instructions, i))),
+                ParsedInstruction::ElseStart => instructions.push(Instruction::Else(find_else_end(&parsed_instructions, i))),
+                ParsedInstruction::ElseEnd => instructions.push(Instruction::EndElse),
+                ParsedInstruction::SeekZero(direction) => instructions.push(Instruction::SeekZero(*direction)),
+                ParsedInstruction::Rotate(amount) => instructions.push(Instruction::Rotate(*amount)),
+                ParsedInstruction::GotoCell => instructions.push(Instruction::GotoCell),
                 ParsedInstruction::Read => instructions.push(Instruction::Read),
+                ParsedInstruction::ReadLine => instructions.push(Instruction::ReadLine),
+                ParsedInstruction::ReadUntil(delimiter) => instructions.push(Instruction::ReadUntil(*delimiter)),
                 ParsedInstruction::Write => instructions.push(Instruction::Write),
+                ParsedInstruction::WriteRegion => instructions.push(Instruction::WriteRegion),
+                ParsedInstruction::Newline => instructions.push(Instruction::WriteByte(b'\n')),
+                ParsedInstruction::WriteLiteral(bytes) => instructions.push(Instruction::WriteLiteral(bytes.clone())),
                 ParsedInstruction::Quote(value) => instructions.push(Instruction::Quote(*value)),
+                ParsedInstruction::QuoteBytes(bytes) => instructions.push(Instruction::QuoteBytes(bytes.clone())),
+                ParsedInstruction::Assert(expected) => instructions.push(Instruction::Assert(*expected)),
                 ParsedInstruction::Send(reference) => instructions.push(Instruction::Send(reference.clone())),
                 ParsedInstruction::Receive(reference) => instructions.push(Instruction::Receive(reference.clone())),
-                ParsedInstruction::Call(procedure, region) => instructions.push(Instruction::Call(procedure.to_string(), region.clone())),
+                ParsedInstruction::Transfer(source, destination) => instructions.push(Instruction::Transfer(source.clone(), destination.clone())),
+                ParsedInstruction::Compare(reference) => instructions.push(Instruction::Compare(reference.clone())),
+                ParsedInstruction::Not => instructions.push(Instruction::Not),
+                ParsedInstruction::And(reference) => instructions.push(Instruction::And(reference.clone())),
+                ParsedInstruction::Or(reference) => instructions.push(Instruction::Or(reference.clone())),
+                ParsedInstruction::Xor(reference) => instructions.push(Instruction::Xor(reference.clone())),
+                ParsedInstruction::Shl(amount) => instructions.push(Instruction::Shl(*amount)),
+                ParsedInstruction::Shr(amount) => instructions.push(Instruction::Shr(*amount)),
+                ParsedInstruction::Mod(divisor) => instructions.push(Instruction::Mod(*divisor)),
+                ParsedInstruction::CopyRel(offset) => instructions.push(Instruction::CopyRel(*offset)),
+                ParsedInstruction::SumRegion => instructions.push(Instruction::SumRegion),
+                ParsedInstruction::MinRegion => instructions.push(Instruction::MinRegion),
+                ParsedInstruction::MaxRegion => instructions.push(Instruction::MaxRegion),
+                ParsedInstruction::RuntimeInfo(kind) => instructions.push(Instruction::RuntimeInfo(*kind)),
+                ParsedInstruction::Call(procedure, region, window, argument) => instructions.push(Instruction::Call(procedure.to_string(), region.clone(), window.clone(), *argument)),
+                ParsedInstruction::CallTransient(procedure, size, argument) => instructions.push(Instruction::CallTransient(procedure.to_string(), *size, *argument)),
+                ParsedInstruction::ResetScratch(region) => instructions.push(Instruction::ResetScratch(region.clone())),
             }
         }
         return Procedure {
             name: name.to_string(),
             is_anonymous,
             instructions,
+            spans,
         }
     }
 
-    pub fn execute(&self, region: &mut Region, mut pointer: usize, regions: &HashMap<String, RefCell<Region>>, back_reference: &str) -> Option<Call> {
-        if (pointer == 0) && (self.instructions.is_empty()) {
+    pub fn len(&self) -> usize {
+        return self.instructions.len();
+    }
+
+    pub fn is_empty(&self) -> bool {
+        return self.instructions.is_empty();
+    }
+
+    pub fn instruction(&self, index: usize) -> Option<&Instruction> {
+        return self.instructions.get(index);
+    }
+
+    pub fn span(&self, index: usize) -> Option<Span> {
+        return self.spans.get(index).copied();
+    }
+
+    // None past the last instruction, where the procedure returns
+    fn following(&self, index: usize) -> Option<usize> {
+        let next: usize = usize::wrapping_add(index, 1);
+        if (next == 0) || (next == self.instructions.len()) {
             return None;
         }
-        let mut return_pointer: Option<usize>;
+        return Some(next);
+    }
+
+    // One match per instruction, which works out both what the instruction does and where execution goes next
+    pub fn execute(&self, region: &mut Region, mut pointer: usize, context: &mut ExecutionContext) -> Result<Exit, RuntimeError> {
+        if (pointer == 0) && (self.instructions.is_empty()) {
+            return Ok(Exit::Returned);
+        }
+        let regions: &BTreeMap<String, RefCell<Region>> = context.regions;
         loop {
-            match &self.instructions[pointer] {
-                Instruction::LoopStart(location) if region.get() == 0 => pointer = *location,
-                Instruction::LoopEnd(location) if region.get() != 0 => pointer = *location,
-                _ => {},
+            let instruction: &Instruction = &self.instructions[pointer];
+            context.metrics.instructions_executed += 1;
+            check_limits(context)?;
+            // Checked before the instruction runs, so that it is exactly where the program stopped
+            if context.interrupt.is_some_and(|interrupt| interrupt.load(AtomicOrdering::Relaxed)) {
+                return Err(RuntimeError::Interrupted {
+                    procedure: self.name.to_string(),
+                    instruction: pointer,
+                    region: region.name.to_string(),
+                    pointer: region.pointer(),
+                });
             }
-            let next: usize = usize::wrapping_add(pointer, 1);
-            if (next == 0) || (next == self.instructions.len()) {
-                return_pointer = None;
-            } else {
-                return_pointer = Some(next);
+            if let Some(hits) = context.coverage.as_deref_mut() {
+                hits[pointer] = true;
+            }
+            if let Some(trace) = context.trace.as_deref_mut() {
+                trace.record(TraceEntry {
+                    procedure: self.name.clone(),
+                    instruction: pointer,
+                    region: region.name.clone(),
+                    pointer: region.pointer(),
+                    // Indexed rather than read, so that tracing doesn't show up in the access counts
+                    cell: region[region.pointer()],
+                });
             }
-            match &self.instructions[pointer] {
+            if region.is_readonly() && instruction.writes_region() {
+                return Err(read_only(region));
+            }
+            // Where to carry on afterwards, which a jump replaces with the instruction after its target. Calls take
+            // it along as the place to come back to
+            let mut return_pointer: Option<usize> = self.following(pointer);
+            match instruction {
+                // A jump goes straight past its target rather than to it. Every target is a `]`, `[`, else or end of
+                // else, none of which do anything of their own once the jump has been decided here, so skipping them
+                // doesn't lose anything, and a skipped loop never runs its closing bracket
+                Instruction::LoopStart(location) => {
+                    if region.get() == 0 {
+                        return_pointer = self.following(*location);
+                    }
+                },
+                Instruction::LoopEnd(location) => {
+                    if region.get() != 0 {
+                        return_pointer = self.following(*location);
+                    }
+                },
+                Instruction::Else(location) => return_pointer = self.following(*location),
+                Instruction::EndElse => {},
+                Instruction::Right if context.pointer_mode == PointerMode::Strict => {
+                    region.checked_right().ok_or_else(|| out_of_bounds(region))?;
+                },
+                Instruction::Left if context.pointer_mode == PointerMode::Strict => {
+                    region.checked_left().ok_or_else(|| out_of_bounds(region))?;
+                },
                 Instruction::Right => region.right(),
                 Instruction::Left => region.left(),
-                Instruction::Reset => region.goto(0),
+                Instruction::Reset => region.goto(region.bounds().start),
+                Instruction::Plus if context.arithmetic_mode == ArithmeticMode::Checked => {
+                    region.checked_increment().ok_or_else(|| RuntimeError::ArithmeticOverflow {
+                        region: region.name.to_string(),
+                        pointer: region.pointer(),
+                    })?;
+                },
+                Instruction::Minus if context.arithmetic_mode == ArithmeticMode::Checked => {
+                    region.checked_decrement().ok_or_else(|| RuntimeError::ArithmeticOverflow {
+                        region: region.name.to_string(),
+                        pointer: region.pointer(),
+                    })?;
+                },
                 Instruction::Plus => region.increment(),
                 Instruction::Minus => region.decrement(),
+                Instruction::SeekZero(direction) => {
+                    let wrapping: bool = context.pointer_mode == PointerMode::Wrapping;
+                    if region.seek_zero(*direction, wrapping).is_none() {
+                        if wrapping {
+                            return Err(RuntimeError::NoZeroCell(region.name.to_string()));
+                        }
+                        return Err(out_of_bounds(region));
+                    }
+                },
+                Instruction::Rotate(amount) => region.rotate(*amount),
+                // Inside of a window, the index counts from the start of the window
+                Instruction::GotoCell => {
+                    let bounds: Range<usize> = region.bounds();
+                    let location: usize = usize::from(region.get());
+                    if context.pointer_mode == PointerMode::Strict {
+                        region.checked_goto(bounds.start + location).ok_or_else(|| RuntimeError::PointerOutOfBounds {
+                            region: region.name.to_string(),
+                            pointer: bounds.start + location,
+                        })?;
+                    } else {
+                        region.goto(bounds.start + (location % bounds.len()));
+                    }
+                },
                 Instruction::Read => {
-                    let mut buf: [u8; 1] = [0; 1];
-                    // No reason not to just panic if this fails, so the unwrap stays
-                    io::stdin().read_exact(&mut buf).unwrap();
-                    region.set(buf[0]);
+                    // The cell is left untouched once input runs out, unless that is an error
+                    match read_byte(context)? {
+                        Some(value) => region.set(value),
+                        None if context.eof_mode == EofMode::Error => return Err(unexpected_eof(region)),
+                        None => {},
+                    }
                 },
-                // Same deal with the unwrap here
-                Instruction::Write => io::stdout().write_all(&[region.get()]).unwrap(),
+                Instruction::ReadLine => read_until(region, context, b'\n', true)?,
+                Instruction::ReadUntil(delimiter) => read_until(region, context, *delimiter, false)?,
+                Instruction::Write => {
+                    check_initialized(region)?;
+                    match context.output_encoding {
+                        OutputEncoding::Raw => write_output(context, &[region.get()])?,
+                        OutputEncoding::Codepoint => {
+                            let mut encoded: [u8; 4] = [0; 4];
+                            write_output(context, char::from(region.get()).encode_utf8(&mut encoded).as_bytes())?;
+                        },
+                    }
+                },
+                // The whole region from its first cell, wherever the head is, or the whole window inside of one
+                Instruction::WriteRegion => {
+                    check_all_initialized(region)?;
+                    match context.output_encoding {
+                        OutputEncoding::Raw => write_output(context, region.cells())?,
+                        OutputEncoding::Codepoint => {
+                            let encoded: String = region.cells().iter().map(|cell| char::from(*cell)).collect();
+                            write_output(context, encoded.as_bytes())?;
+                        },
+                    }
+                },
+                Instruction::WriteByte(value) => write_output(context, &[*value])?,
+                Instruction::WriteLiteral(bytes) => write_output(context, bytes)?,
                 Instruction::Quote(value) => region.set(*value),
-                Instruction::Send(RegionReference::Named(region_name)) => {
-                    if let Ok(mut reference) = regions.get(region_name).unwrap().try_borrow_mut() {
-                        reference.set(region.get());
+                Instruction::Assert(expected) if !context.ignore_assertions => {
+                    check_initialized(region)?;
+                    let actual: u8 = region.get();
+                    if actual != *expected {
+                        return Err(RuntimeError::AssertionFailed {
+                            expected: *expected,
+                            actual,
+                            procedure: self.name.to_string(),
+                            region: region.name.to_string(),
+                            pointer: region.pointer(),
+                        });
+                    }
+                },
+                Instruction::Assert(_) => {},
+                // The same as a quote and a `>` for each byte, so the head ends up just past the last one
+                Instruction::QuoteBytes(bytes) => {
+                    for byte in bytes {
+                        region.set(*byte);
+                        move_right(region, context.pointer_mode)?;
                     }
                 },
-                Instruction::Send(RegionReference::BackReference) => {
-                    if let Ok(mut reference) = regions.get(back_reference).unwrap().try_borrow_mut() {
-                        reference.set(region.get());
+                Instruction::Send(reference) => {
+                    let region_name: &str = resolve_reference(reference, context.back_reference);
+                    match find_other(context, region_name)?.try_borrow_mut() {
+                        Ok(mut other) => {
+                            if context.aligned_transfers {
+                                check_alignment(region, &other)?;
+                            }
+                            if other.is_readonly() {
+                                return Err(read_only(&other));
+                            }
+                            check_initialized(region)?;
+                            other.set(region.get());
+                        },
+                        Err(_) => record_conflict(context, &region.name, region_name),
                     }
                 },
-                Instruction::Receive(RegionReference::Named(region_name)) => {
-                    if let Ok(reference) = regions.get(region_name).unwrap().try_borrow() {
-                        region.set(reference.get());
+                Instruction::Receive(reference) => {
+                    let region_name: &str = resolve_reference(reference, context.back_reference);
+                    match find_other(context, region_name)?.try_borrow() {
+                        Ok(other) => {
+                            if context.aligned_transfers {
+                                check_alignment(region, &other)?;
+                            }
+                            check_initialized(&other)?;
+                            region.set(other.get());
+                        },
+                        Err(_) => record_conflict(context, &region.name, region_name),
                     }
                 },
-                Instruction::Receive(RegionReference::BackReference) => {
-                    if let Ok(reference) = regions.get(back_reference).unwrap().try_borrow() {
-                        region.set(reference.get());
+                Instruction::Transfer(source, destination) => {
+                    let source_name: &str = resolve_reference(source, context.back_reference);
+                    let destination_name: &str = resolve_reference(destination, context.back_reference);
+                    // Copying a cell onto itself is the only case where both borrows can't coexist
+                    if source_name != destination_name {
+                        let Ok(source_region) = find_other(context, source_name)?.try_borrow() else {
+                            record_conflict(context, &region.name, source_name);
+                            return Err(RuntimeError::RegionConflict(source_name.to_string()));
+                        };
+                        let Ok(mut destination_region) = find_other(context, destination_name)?.try_borrow_mut() else {
+                            record_conflict(context, &region.name, destination_name);
+                            return Err(RuntimeError::RegionConflict(destination_name.to_string()));
+                        };
+                        if destination_region.is_readonly() {
+                            return Err(read_only(&destination_region));
+                        }
+                        if context.aligned_transfers {
+                            check_alignment(&source_region, &destination_region)?;
+                        }
+                        check_initialized(&source_region)?;
+                        destination_region.set(source_region.get());
                     }
                 },
-                Instruction::Call(procedure_name, None) => {
-                    return Some(Call {
+                Instruction::Compare(reference) => {
+                    check_initialized(region)?;
+                    match region.get().cmp(&other_cell(region, reference, context)?) {
+                        Ordering::Less => region.set(u8::MAX),
+                        Ordering::Equal => region.set(0),
+                        Ordering::Greater => region.set(1),
+                    }
+                },
+                Instruction::Not => {
+                    check_initialized(region)?;
+                    region.set(!region.get());
+                },
+                Instruction::And(reference) => {
+                    check_initialized(region)?;
+                    region.set(region.get() & other_cell(region, reference, context)?);
+                },
+                Instruction::Or(reference) => {
+                    check_initialized(region)?;
+                    region.set(region.get() | other_cell(region, reference, context)?);
+                },
+                Instruction::Xor(reference) => {
+                    check_initialized(region)?;
+                    region.set(region.get() ^ other_cell(region, reference, context)?);
+                },
+                // Shifting a byte by eight or more leaves nothing of it
+                Instruction::Shl(amount) => {
+                    check_initialized(region)?;
+                    region.set(region.get().checked_shl(u32::from(*amount)).unwrap_or(0));
+                },
+                Instruction::Shr(amount) => {
+                    check_initialized(region)?;
+                    region.set(region.get().checked_shr(u32::from(*amount)).unwrap_or(0));
+                },
+                Instruction::Mod(divisor) => {
+                    check_initialized(region)?;
+                    region.set(region.get() % *divisor);
+                },
+                Instruction::CopyRel(offset) => {
+                    check_initialized(region)?;
+                    if region.copy_relative(*offset, context.pointer_mode == PointerMode::Wrapping).is_none() {
+                        return Err(out_of_bounds(region));
+                    }
+                },
+                // The folds cover the current cell too, and only the window when there is one
+                Instruction::SumRegion if context.arithmetic_mode == ArithmeticMode::Checked => {
+                    check_all_initialized(region)?;
+                    let sum: u8 = region.cells().iter().try_fold(0, |sum: u8, cell| sum.checked_add(*cell)).ok_or_else(|| RuntimeError::ArithmeticOverflow {
+                        region: region.name.to_string(),
+                        pointer: region.pointer(),
+                    })?;
+                    region.set(sum);
+                },
+                Instruction::SumRegion => {
+                    check_all_initialized(region)?;
+                    region.set(region.cells().iter().fold(0, |sum: u8, cell| sum.wrapping_add(*cell)));
+                },
+                Instruction::MinRegion => {
+                    check_all_initialized(region)?;
+                    region.set(region.cells().iter().copied().min().unwrap());
+                },
+                Instruction::MaxRegion => {
+                    check_all_initialized(region)?;
+                    region.set(region.cells().iter().copied().max().unwrap());
+                },
+                Instruction::RuntimeInfo(InfoKind::Interpreter) => region.set(INTERPRETER_ID),
+                Instruction::RuntimeInfo(InfoKind::CellWidth) => region.set(u8::BITS as u8),
+                Instruction::RuntimeInfo(InfoKind::RegionCount) => region.set(u8::try_from(regions.len()).unwrap_or(u8::MAX)),
+                Instruction::ResetScratch(region_name) => {
+                    let Ok(mut scratch) = find_region(regions, region_name)?.try_borrow_mut() else {
+                        record_conflict(context, &region.name, region_name);
+                        return Err(RuntimeError::RegionConflict(region_name.to_string()));
+                    };
+                    scratch.clear();
+                },
+                // Staying on the current region also means staying inside of its window, if there is one
+                Instruction::Call(procedure_name, None, _, argument) => {
+                    return Ok(Exit::Call(Call {
                         procedure: procedure_name.to_string(),
                         region: region.name.to_string(),
+                        window: region.window(),
+                        argument: call_argument(region, *argument)?,
                         return_pointer,
-                    });
+                        transient: None,
+                    }));
                 },
-                Instruction::Call(procedure_name, Some(RegionReference::BackReference)) => {
-                    return Some(Call {
+                Instruction::Call(procedure_name, Some(RegionReference::BackReference), window, argument) => {
+                    return Ok(Exit::Call(Call {
                         procedure: procedure_name.to_string(),
-                        region: back_reference.to_string(),
+                        region: context.back_reference.to_string(),
+                        window: window.clone(),
+                        argument: call_argument(region, *argument)?,
                         return_pointer,
-                    });
+                        transient: None,
+                    }));
                 },
-                Instruction::Call(procedure_name, Some(RegionReference::Named(region_name))) => {
-                    return Some(Call {
+                Instruction::Call(procedure_name, Some(RegionReference::Named(region_name)), window, argument) => {
+                    return Ok(Exit::Call(Call {
                         procedure: procedure_name.to_string(),
                         region: region_name.to_string(),
+                        window: window.clone(),
+                        argument: call_argument(region, *argument)?,
                         return_pointer,
-                    });
+                        transient: None,
+                    }));
+                },
+                // Named after the procedure, and the `@` keeps it from clashing with anything declared in the source
+                Instruction::CallTransient(procedure_name, size, argument) => {
+                    return Ok(Exit::Call(Call {
+                        procedure: procedure_name.to_string(),
+                        region: format!("{}@new", procedure_name),
+                        window: None,
+                        argument: call_argument(region, *argument)?,
+                        return_pointer,
+                        transient: Some(*size),
+                    }));
                 },
-                _ => {},
             }
             if let Some(next) = return_pointer {
+                if context.single_step {
+                    return Ok(Exit::Paused(next));
+                }
                 pointer = next;
             } else {
-                return None;
+                return Ok(Exit::Returned);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use crate::{interpreter::program::{ArithmeticMode, EofMode, PointerMode, Program, ProgramOptions, RuntimeError}, parser::parser::ParseError};
+
+    fn run(source: &str, options: &ProgramOptions) -> (Result<Vec<u8>, RuntimeError>, Program) {
+        let mut program: Program = Program::from_string_with_options(source, options).unwrap();
+        return (program.run_with_bytes(&[]), program);
+    }
+
+    fn run_with_input(source: &str, input: &[u8]) -> (Result<Vec<u8>, RuntimeError>, Program) {
+        let mut program: Program = Program::from_string(source).unwrap();
+        return (program.run_with_bytes(input), program);
+    }
+
+    fn cells(program: &Program, region: &str) -> Vec<u8> {
+        return program.region(region).unwrap().bytes;
+    }
+
+    fn checked() -> ProgramOptions {
+        return ProgramOptions { arithmetic_mode: ArithmeticMode::Checked, ..ProgramOptions::default() };
+    }
+
+    fn strict_pointer() -> ProgramOptions {
+        return ProgramOptions { pointer_mode: PointerMode::Strict, ..ProgramOptions::default() };
+    }
+
+    fn poisoned() -> ProgramOptions {
+        return ProgramOptions { poison_regions: true, ..ProgramOptions::default() };
+    }
+
+    #[test]
+    fn sends_between_regions_of_different_sizes_use_each_head() {
+        let source: &str = "region main[1]; region big[8]; proc main: (>>>)@big \"05 ^big \"00 &big . (+ ^main)@big . ;";
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [5, 6]);
+        assert_eq!(cells(&program, "big"), [0, 0, 0, 6, 0, 0, 0, 0]);
+        assert_eq!(program.region("big").unwrap().pointer, 3);
+        assert_eq!(cells(&program, "main"), [6]);
+    }
+
+    #[test]
+    fn aligned_transfers_reject_heads_out_of_the_other_region() {
+        let options: ProgramOptions = ProgramOptions { aligned_transfers: true, ..ProgramOptions::default() };
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region big[8]; proc main: (>>>)@big ^big ;", &options);
+        assert!(matches!(output, Err(RuntimeError::MisalignedTransfer { ref region, pointer: 3, ref other }) if (region == "big") && (other == "main")));
+        assert_eq!(cells(&program, "big"), [0; 8]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region big[8]; proc main: \"07 ^big &big . ;", &options);
+        assert_eq!(output.unwrap(), [7]);
+    }
+
+    #[test]
+    fn shifts_lose_bits_past_either_end() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: \"81 *shl 1 . \"81 *shr 1 . \"81 *shl 0 . \"81 *shr 0 . \"ff *shl 8 . \"ff *shr 9 . \"0f *shl 4 . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x02, 0x40, 0x81, 0x81, 0x00, 0x00, 0xf0]);
+    }
+
+    #[test]
+    fn shifts_ignore_checked_arithmetic() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: \"ff *shl 1 . ;", &ProgramOptions::strict());
+        assert_eq!(output.unwrap(), [0xfe]);
+    }
+
+    #[test]
+    fn else_block_runs_when_the_cell_is_zero_on_entry() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: [-]{> \"45 . <} \"01 . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x45, 0x01]);
+    }
+
+    #[test]
+    fn else_block_is_skipped_when_the_loop_ran() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: \"03 [- > + <]{\"45 .} > . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x03]);
+        assert_eq!(cells(&program, "main"), [0, 3]);
+    }
+
+    #[test]
+    fn else_blocks_nest() {
+        let source: &str = "region main[2]; proc main: [-]{> [-]{\"41 . [-]} [-]{\"42 .} \"01 [-]{\"43 .} <} ;";
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &ProgramOptions::default());
+        assert_eq!(output.unwrap(), b"AB");
+    }
+
+    fn instructions_executed(source: &str) -> u64 {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &ProgramOptions::default());
+        output.unwrap();
+        return program.instructions_executed();
+    }
+
+    // A jump lands past its target, so the bracket it jumped to never runs as an instruction of its own
+    #[test]
+    fn skipped_loops_never_reach_their_closing_bracket() {
+        assert_eq!(instructions_executed("region main[1]; proc main: [+] ;"), 1);
+        assert_eq!(instructions_executed("region main[1]; proc main: [] + ;"), 2);
+        assert_eq!(instructions_executed("region main[1]; proc main: [[+]+] ;"), 1);
+    }
+
+    #[test]
+    fn repeating_loops_run_each_bracket_once_per_pass() {
+        // `+++`, then three passes of `-` and `]` with the first `[` only run on entry
+        assert_eq!(instructions_executed("region main[1]; proc main: +++[-] ;"), 3 + 1 + (3 * 2));
+        // The inner loop clears the cell on the first pass, so the outer `]` falls through
+        assert_eq!(instructions_executed("region main[1]; proc main: ++[[-]] ;"), 2 + 1 + 1 + (2 * 2) + 1);
+    }
+
+    #[test]
+    fn tight_loops_leave_the_cell_and_head_right() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: +++[>+<-] > [[-]>+<] > . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [1]);
+        assert_eq!(cells(&program, "main"), [0, 0, 1, 0]);
+        assert_eq!(program.region("main").unwrap().pointer, 2);
+    }
+
+    // Outputs the interpreter gave before the two matches of the execution loop became one
+    #[test]
+    fn single_dispatch_matches_earlier_outputs() {
+        let source: &str = "region main[4]; region other[2]; proc main: \"05 [> \"02 [>+<-] <-] >> . ~ [-]{\"41 .} ?> . \"03 [-(+)@other] &other . low@other &other . ; proc low: - [+>]{\"07} ;";
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x0a, 0x41, 0x00, 0x03, 0x00]);
+        let mut math: Program = Program::from_string(include_str!("../examples/math.cae")).unwrap();
+        assert_eq!(math.run_with_bytes(&[]).unwrap(), [0x31, 0xc6]);
+        let mut echo: Program = Program::from_string(include_str!("../examples/echo.cae")).unwrap();
+        assert_eq!(echo.run_with_bytes(b"Hello, world!\n").unwrap(), b"Hello, world!\n");
+    }
+
+    #[test]
+    fn arithmetic_wraps_by_default() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: - . + . \"ff + . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0xff, 0x00, 0x00]);
+    }
+
+    #[test]
+    fn checked_arithmetic_stops_at_either_end() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: > - ;", &checked());
+        assert!(matches!(output, Err(RuntimeError::ArithmeticOverflow { pointer: 1, .. })));
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: \"fe + . + ;", &checked());
+        assert!(matches!(output, Err(RuntimeError::ArithmeticOverflow { pointer: 0, .. })));
+        assert_eq!(cells(&program, "main"), [0xff]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: \"fe + - - . ;", &checked());
+        assert_eq!(output.unwrap(), [0xfd]);
+    }
+
+    #[test]
+    fn pointer_wraps_by_default() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: < + >> + ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [0, 1, 1]);
+        assert_eq!(program.region("main").unwrap().pointer, 1);
+    }
+
+    #[test]
+    fn strict_pointer_stops_at_either_end() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: < ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: >> + > ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+        assert_eq!(cells(&program, "main"), [0, 0, 1]);
+    }
+
+    #[test]
+    fn seek_wraps_around_and_fails_without_a_zero() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: > + > + > + ?> ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(program.region("main").unwrap().pointer, 0);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: + > + ?< ;", &ProgramOptions::default());
+        assert!(matches!(output, Err(RuntimeError::NoZeroCell(name)) if name == "main"));
+    }
+
+    #[test]
+    fn strict_seek_stops_at_the_end_of_the_region() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: > + > + > + ?> ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: > + > + > ?< ;", &strict_pointer());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(program.region("main").unwrap().pointer, 3);
+    }
+
+    #[test]
+    fn goto_wraps_the_cell_into_the_region() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: \"06 *goto + ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [6, 0, 1, 0]);
+        assert_eq!(program.region("main").unwrap().pointer, 2);
+    }
+
+    #[test]
+    fn strict_goto_rejects_cells_past_the_end() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: \"03 *goto + ;", &strict_pointer());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [3, 0, 0, 1]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: \"06 *goto + ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { ref region, pointer: 6 }) if region == "main"));
+    }
+
+    #[test]
+    fn poison_catches_reads_of_unwritten_cells() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: > . ;", &poisoned());
+        assert!(matches!(output, Err(RuntimeError::UninitializedRead { pointer: 1, .. })));
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; proc main: > \"07 . ;", &poisoned());
+        assert_eq!(output.unwrap(), [0x07]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[2]; region other[1]; proc main: > ^other ;", &poisoned());
+        assert!(matches!(output, Err(RuntimeError::UninitializedRead { pointer: 1, .. })));
+    }
+
+    #[test]
+    fn poison_leaves_initialized_regions_alone() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region table[3] = {1, 2}; proc main: show@table ; proc show: . > . > . ;", &poisoned());
+        assert_eq!(output.unwrap(), [1, 2, 0]);
+    }
+
+    #[test]
+    fn reading_past_the_input_leaves_the_cell_by_default() {
+        let mut program: Program = Program::from_string("region main[1]; proc main: \"07 , . , . ;").unwrap();
+        assert_eq!(program.run_with_bytes(&[0x41]).unwrap(), [0x41, 0x41]);
+    }
+
+    #[test]
+    fn strict_options_turn_every_check_on() {
+        let options: ProgramOptions = ProgramOptions::strict();
+        assert_eq!(options.arithmetic_mode, ArithmeticMode::Checked);
+        assert_eq!(options.pointer_mode, PointerMode::Strict);
+        assert_eq!(options.eof_mode, EofMode::Error);
+        let mut program: Program = Program::from_string_with_options("region main[1]; proc main: , . , ;", &options).unwrap();
+        assert!(matches!(program.run_with_bytes(&[0x41]), Err(RuntimeError::UnexpectedEof { pointer: 0, .. })));
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: - ;", &options);
+        assert!(matches!(output, Err(RuntimeError::ArithmeticOverflow { .. })));
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: > ;", &options);
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn assertions_check_the_current_cell() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: +++=03 . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x03]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region other[2]; proc main: check@other ; proc check: > ++=03 ;", &ProgramOptions::default());
+        match output {
+            Err(RuntimeError::AssertionFailed { expected, actual, procedure, region, pointer }) => {
+                assert_eq!((expected, actual, pointer), (0x03, 0x02, 1));
+                assert_eq!(procedure, "check");
+                assert_eq!(region, "other");
             }
+            other => panic!("expected a failed assertion, got {:?}", other),
         }
     }
+
+    #[test]
+    fn ignored_assertions_are_skipped() {
+        let options: ProgramOptions = ProgramOptions { ignore_assertions: true, ..ProgramOptions::default() };
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: +=ff . ;", &options);
+        assert_eq!(output.unwrap(), [0x01]);
+    }
+
+    #[test]
+    fn readline_stores_the_length_before_the_line() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run_with_input("region main[4]; region second[4]; region third[2]; proc main: *readline (*readline)@second (\"07 *readline)@third ;", b"ab\ncd");
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [3, b'a', b'b', b'\n']);
+        assert_eq!(program.region("main").unwrap().pointer, 0);
+        assert_eq!(cells(&program, "second"), [2, b'c', b'd', 0]);
+        assert_eq!(cells(&program, "third"), [0, 0]);
+    }
+
+    #[test]
+    fn readuntil_consumes_the_delimiter_without_storing_it() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run_with_input("region main[4]; region second[4]; proc main: *readuntil 2c (*readuntil 2c)@second ;", b"ab,c");
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [2, b'a', b'b', 0]);
+        assert_eq!(cells(&program, "second"), [1, b'c', 0, 0]);
+    }
+
+    #[test]
+    fn cmp_stores_the_ordering_as_a_wrapped_byte() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region other[1]; proc main: (\"03)@other \"02 *cmp other . \"03 *cmp other . \"04 *cmp other . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0xff, 0x00, 0x01]);
+    }
+
+    #[test]
+    fn bitwise_ops_combine_with_the_back_reference() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region other[1]; proc main: \"0c (\"0a *and $ . \"0a *or $ . \"0a *xor $ . \"0f *not .)@other ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x08, 0x0e, 0x06, 0xf0]);
+    }
+
+    #[test]
+    fn sum_wraps_unless_arithmetic_is_checked() {
+        let source: &str = "region main[3]; proc main: \"ff > \"02 > \"01 *sum . ;";
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x02]);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run(source, &checked());
+        assert!(matches!(output, Err(RuntimeError::ArithmeticOverflow { pointer: 2, .. })));
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: \"fc > \"02 > \"01 *sum . ;", &checked());
+        assert_eq!(output.unwrap(), [0xff]);
+    }
+
+    #[test]
+    fn writeall_writes_from_the_first_cell_and_keeps_the_head() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: > \"41 > \"42 *writeall ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0x00, 0x41, 0x42]);
+        assert_eq!(program.region("main").unwrap().pointer, 2);
+    }
+
+    #[test]
+    fn copy_writes_at_an_offset_without_moving() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: \"07 *copy 2 *copy -1 ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [7, 0, 7, 7]);
+        assert_eq!(program.region("main").unwrap().pointer, 0);
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[4]; proc main: \"07 *copy 4 ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+    }
+
+    #[test]
+    fn quoted_bytes_wrap_past_the_end_of_the_region() {
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: > \"{01 02 03} ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), []);
+        assert_eq!(cells(&program, "main"), [3, 1, 2]);
+        assert_eq!(program.region("main").unwrap().pointer, 1);
+        let (output, program): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[3]; proc main: \"{01 02 03} ;", &strict_pointer());
+        assert!(matches!(output, Err(RuntimeError::PointerOutOfBounds { .. })));
+        assert_eq!(cells(&program, "main"), [1, 2, 3]);
+    }
+
+    #[test]
+    fn info_reports_the_interpreter_cell_width_and_region_count() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; region other[1]; proc main: *info id . *info width . *info regions . (+)[4] ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [0xca, 8, 3]);
+    }
+
+    #[test]
+    fn mod_keeps_the_remainder() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: \"17 *mod 10 . \"ff *mod 1 . \"05 *mod 255 . ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), [3, 0, 5]);
+        assert!(matches!(Program::from_string("region main[1]; proc main: *mod 0 ;"), Err(ParseError::MalformedNumber)));
+    }
+
+    #[test]
+    fn escapes_in_literals_decode_to_bytes() {
+        let (output, _): (Result<Vec<u8>, RuntimeError>, Program) = run("region main[1]; proc main: 'a\\x0a\\x41\\t\\\\\\'\\\"' ;", &ProgramOptions::default());
+        assert_eq!(output.unwrap(), b"a\nA\t\\'\"");
+        assert!(matches!(Program::from_string("region main[1];\nproc main: 'ab\\xzz' ;"), Err(ParseError::InvalidEscape(position)) if (position.line, position.column) == (2, 15)));
+    }
 }
@@ -1,10 +1,137 @@
-use std::num::NonZeroUsize;
+use alloc::{boxed::Box, collections::VecDeque, string::String, vec, vec::Vec};
+use core::{cell::RefCell, fmt::Write, num::NonZeroUsize, ops::{Index, Range}, slice::Iter};
+
+pub const POISON: u8 = 0xAA;
+
+// 64-bit FNV-1a, which is more than enough to tell region states apart in tests
+fn fnv1a(bytes: &[u8]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in bytes {
+        hash ^= *byte as u64;
+        hash = u64::wrapping_mul(hash, 0x100000001b3);
+    }
+    return hash;
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegionSnapshot {
+    pub name: String,
+    pub bytes: Vec<u8>,
+    pub pointer: usize,
+}
+
+impl RegionSnapshot {
+    pub fn checksum(&self) -> u64 {
+        return fnv1a(&self.bytes);
+    }
+}
+
+// One line per changed cell, for printing alongside a failed comparison of two regions
+pub fn format_diff(diff: &[(usize, u8, u8)]) -> String {
+    let mut text: String = String::new();
+    for (index, old, new) in diff {
+        writeln!(text, "{:>8}: {:#04x} -> {:#04x}", index, old, new).unwrap();
+    }
+    return text;
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    Left,
+    Right,
+}
+
+// How much of the traffic through a region's cells gets counted, which is off unless asked for since every read
+// and write pays for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AccessTracking {
+    #[default]
+    Off,
+    Counts,
+    // Also counts the accesses to each cell separately
+    Histogram,
+}
+
+// Which positions of the head get recorded, which is off unless asked for since every move pays for it. A head that
+// goes back and forth over the same few cells shows up far more clearly here than in the access counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PointerHistory {
+    #[default]
+    Off,
+    All,
+    // Only the most recent positions, so that long runs stay within a fixed amount of memory
+    Last(NonZeroUsize),
+}
+
+// Incrementing or decrementing a cell counts as both a read and a write. Looking at a region from the outside, through
+// a snapshot, view or index, isn't counted
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct AccessStats {
+    pub reads: u64,
+    pub writes: u64,
+    // Reads and writes per cell, indexed the same way as the region
+    pub histogram: Option<Vec<u64>>,
+}
+
+// The compile time definition of a region, from which a fresh runtime region is built for every run
+#[derive(Debug, Clone)]
+pub struct RegionTemplate {
+    pub name: String,
+    pub size: NonZeroUsize,
+    pub initial_bytes: Box<[u8]>,
+    // Declared with an initializer, so every cell counts as written even when the region is poisoned
+    pub initialized: bool,
+    pub readonly: bool,
+}
+
+impl RegionTemplate {
+    pub fn new(name: &str, size: NonZeroUsize) -> RegionTemplate {
+        return RegionTemplate {
+            name: String::from(name),
+            size,
+            initial_bytes: vec![0; size.get()].into_boxed_slice(),
+            initialized: false,
+            readonly: false,
+        };
+    }
+
+    // Cells past the end of the initializer are zero
+    pub fn with_initializer(mut self, bytes: &[u8]) -> RegionTemplate {
+        self.initial_bytes[..bytes.len()].copy_from_slice(bytes);
+        self.initialized = true;
+        return self;
+    }
+
+    pub fn with_readonly(mut self, readonly: bool) -> RegionTemplate {
+        self.readonly = readonly;
+        return self;
+    }
+}
+
+// A borrowed look at a live region, for inspecting it between steps without copying
+#[derive(Debug, Clone, Copy)]
+pub struct RegionView<'a> {
+    pub name: &'a str,
+    pub bytes: &'a [u8],
+    pub pointer: usize,
+}
 
 #[derive(Debug)]
 pub struct Region {
     pub name: String,
     bytes: Box<[u8]>,
     pointer: usize,
+    // One bit per cell, only tracked once the region has been poisoned
+    written: Option<Box<[u64]>>,
+    // Set while a procedure called on part of the region is running, confining movement to those cells
+    window: Option<Range<usize>>,
+    // Behind a RefCell so that reads through a shared reference can be counted too
+    access: Option<RefCell<AccessStats>>,
+    // Only enforced by the instructions, the methods below write to it all the same
+    readonly: bool,
+    // Oldest first, along with how many positions it may hold
+    history: Option<(VecDeque<usize>, usize)>,
 }
 
 impl Region {
@@ -13,42 +140,500 @@ impl Region {
             name: String::from(name),
             bytes: vec![0; size.get()].into_boxed_slice(),
             pointer: 0,
+            written: None,
+            window: None,
+            access: None,
+            readonly: false,
+            history: None,
+        };
+    }
+
+    pub fn from_template(template: &RegionTemplate) -> Region {
+        return Region {
+            name: template.name.clone(),
+            bytes: template.initial_bytes.clone(),
+            pointer: 0,
+            written: None,
+            window: None,
+            access: None,
+            readonly: template.readonly,
+            history: None,
         };
     }
 
+    // Fills the region with the sentinel and starts tracking writes, so that reading a cell that was never written
+    // can be caught even though the sentinel is itself a legal value
+    pub fn poison(&mut self) -> () {
+        self.bytes.fill(POISON);
+        self.written = Some(vec![0; self.bytes.len().div_ceil(64)].into_boxed_slice());
+    }
+
+    // Zeroes every cell and puts the head back on the first one, or on the first cell of the window inside of one. A
+    // poisoned region is filled with the sentinel again instead, with none of its cells written. Access counters are
+    // kept, since clearing isn't something the program reads or writes
+    pub fn clear(&mut self) -> () {
+        match &mut self.written {
+            Some(written) => {
+                self.bytes.fill(POISON);
+                written.fill(0);
+            },
+            None => self.bytes.fill(0),
+        }
+        self.pointer = self.bounds().start;
+        self.record_move();
+    }
+
+    pub fn is_readonly(&self) -> bool {
+        return self.readonly;
+    }
+
+    pub fn is_initialized(&self) -> bool {
+        match &self.written {
+            Some(written) => return (written[self.pointer / 64] & (1 << (self.pointer % 64))) != 0,
+            None => return true,
+        }
+    }
+
+    fn mark_written(&mut self) -> () {
+        self.mark_written_at(self.pointer);
+    }
+
+    fn mark_written_at(&mut self, index: usize) -> () {
+        if let Some(written) = &mut self.written {
+            written[index / 64] |= 1 << (index % 64);
+        }
+        self.record_access(0..0, index..(index + 1));
+    }
+
+    // Starts counting from zero again whenever it's called, even if the tracking stays the same
+    pub fn set_access_tracking(&mut self, tracking: AccessTracking) -> () {
+        self.access = match tracking {
+            AccessTracking::Off => None,
+            AccessTracking::Counts => Some(RefCell::new(AccessStats::default())),
+            AccessTracking::Histogram => Some(RefCell::new(AccessStats { histogram: Some(vec![0; self.bytes.len()]), ..AccessStats::default() })),
+        };
+    }
+
+    pub fn access_stats(&self) -> Option<AccessStats> {
+        return self.access.as_ref().map(|access| access.borrow().clone());
+    }
+
+    fn record_access(&self, reads: Range<usize>, writes: Range<usize>) -> () {
+        let Some(access) = &self.access else {
+            return;
+        };
+        let mut access = access.borrow_mut();
+        access.reads += reads.len() as u64;
+        access.writes += writes.len() as u64;
+        if let Some(histogram) = &mut access.histogram {
+            for cell in reads.chain(writes) {
+                histogram[cell] += 1;
+            }
+        }
+    }
+
+    // Starts from just the current position whenever it's called, like the access counters
+    pub fn set_pointer_history(&mut self, history: PointerHistory) -> () {
+        let limit: usize = match history {
+            PointerHistory::Off => {
+                self.history = None;
+                return;
+            },
+            PointerHistory::All => usize::MAX,
+            PointerHistory::Last(limit) => limit.get(),
+        };
+        self.history = Some((VecDeque::from([self.pointer]), limit));
+    }
+
+    // Every position the head has been at while recording, oldest first. Staying on the same cell isn't a move, so
+    // consecutive positions always differ
+    pub fn pointer_history(&self) -> Option<Vec<usize>> {
+        return self.history.as_ref().map(|(positions, _)| positions.iter().copied().collect());
+    }
+
+    fn record_move(&mut self) -> () {
+        let Some((positions, limit)) = &mut self.history else {
+            return;
+        };
+        if positions.back() == Some(&self.pointer) {
+            return;
+        }
+        if positions.len() == *limit {
+            positions.pop_front();
+        }
+        positions.push_back(self.pointer);
+    }
+
+    // Returns None if the window doesn't fit inside the region. The head only moves to the start of the window if it
+    // was outside of it, so that frames running on the same window can keep picking up where they left off
+    pub fn set_window(&mut self, window: Option<Range<usize>>) -> Option<()> {
+        if let Some(window) = &window {
+            if window.is_empty() || (window.end > self.bytes.len()) {
+                return None;
+            }
+            if !window.contains(&self.pointer) {
+                self.pointer = window.start;
+                self.record_move();
+            }
+        }
+        self.window = window;
+        return Some(());
+    }
+
+    pub fn window(&self) -> Option<Range<usize>> {
+        return self.window.clone();
+    }
+
+    // The cells the head can currently reach, which is the whole region outside of a window
+    pub fn bounds(&self) -> Range<usize> {
+        return self.window.clone().unwrap_or(0..self.bytes.len());
+    }
+
+    // The cells inside of the bounds, for the instructions that fold over the whole region or window at once
+    pub fn cells(&self) -> &[u8] {
+        self.record_access(self.bounds(), 0..0);
+        return &self.bytes[self.bounds()];
+    }
+
+    // The first cell inside of the bounds that was never written, if the region is being tracked at all
+    pub fn first_uninitialized(&self) -> Option<usize> {
+        let written: &[u64] = self.written.as_deref()?;
+        return self.bounds().find(|cell| (written[cell / 64] & (1 << (cell % 64))) == 0);
+    }
+
     pub fn right(&mut self) -> () {
-        if self.pointer == (self.bytes.len() - 1) {
-            self.pointer = 0;
+        let bounds: Range<usize> = self.bounds();
+        if self.pointer == (bounds.end - 1) {
+            self.pointer = bounds.start;
         } else {
             self.pointer += 1;
         }
+        self.record_move();
     }
 
     pub fn left(&mut self) -> () {
-        if self.pointer == 0 {
-            self.pointer = self.bytes.len() - 1;
+        let bounds: Range<usize> = self.bounds();
+        if self.pointer == bounds.start {
+            self.pointer = bounds.end - 1;
         } else {
             self.pointer -= 1;
         }
+        self.record_move();
+    }
+
+    pub fn checked_right(&mut self) -> Option<()> {
+        if self.pointer == (self.bounds().end - 1) {
+            return None;
+        }
+        self.pointer += 1;
+        self.record_move();
+        return Some(());
+    }
+
+    pub fn checked_left(&mut self) -> Option<()> {
+        if self.pointer == self.bounds().start {
+            return None;
+        }
+        self.pointer -= 1;
+        self.record_move();
+        return Some(());
+    }
+
+    // Moves to the nearest zero cell in the given direction, starting with the current one, which is exactly what
+    // `[>]` and `[<]` do. Returns None without moving if there's no zero cell before the scan would have to stop
+    pub fn seek_zero(&mut self, direction: Direction, wrapping: bool) -> Option<()> {
+        let bounds: Range<usize> = self.bounds();
+        let location: Option<usize> = match direction {
+            Direction::Right => self.bytes[self.pointer..bounds.end].iter().position(|byte| *byte == 0)
+                .map(|offset| self.pointer + offset)
+                .or_else(|| if wrapping {
+                    self.bytes[bounds.start..self.pointer].iter().position(|byte| *byte == 0).map(|offset| bounds.start + offset)
+                } else {
+                    None
+                }),
+            Direction::Left => self.bytes[bounds.start..=self.pointer].iter().rposition(|byte| *byte == 0)
+                .map(|offset| bounds.start + offset)
+                .or_else(|| if wrapping {
+                    self.bytes[(self.pointer + 1)..bounds.end].iter().rposition(|byte| *byte == 0).map(|offset| self.pointer + 1 + offset)
+                } else {
+                    None
+                }),
+        };
+        self.pointer = location?;
+        self.record_move();
+        return Some(());
+    }
+
+    // Positive amounts move every cell to the right. The pointer stays where it is, so it ends up over different data.
+    // Which cells have been written moves along with the cells themselves
+    pub fn rotate(&mut self, amount: isize) -> () {
+        let bounds: Range<usize> = self.bounds();
+        let distance: usize = amount.unsigned_abs() % bounds.len();
+        // Rotating left is rotating right by whatever is left of the length
+        let shift: usize = if amount >= 0 { distance } else { (bounds.len() - distance) % bounds.len() };
+        self.bytes[bounds.clone()].rotate_right(shift);
+        let Some(written) = &mut self.written else {
+            return;
+        };
+        let mut flags: Vec<bool> = bounds.clone().map(|cell| (written[cell / 64] & (1 << (cell % 64))) != 0).collect();
+        flags.rotate_right(shift);
+        for (cell, flag) in bounds.zip(flags) {
+            if flag {
+                written[cell / 64] |= 1 << (cell % 64);
+            } else {
+                written[cell / 64] &= !(1 << (cell % 64));
+            }
+        }
+    }
+
+    // The index of the cell `offset` cells away from the head, either wrapping around the bounds or None past them
+    fn relative_index(&self, offset: isize, wrapping: bool) -> Option<usize> {
+        let bounds: Range<usize> = self.bounds();
+        let length: i128 = bounds.len() as i128;
+        let position: i128 = ((self.pointer - bounds.start) as i128) + (offset as i128);
+        if wrapping {
+            return Some(bounds.start + (position.rem_euclid(length) as usize));
+        }
+        if (position < 0) || (position >= length) {
+            return None;
+        }
+        return Some(bounds.start + (position as usize));
+    }
+
+    // The cell `offset` cells away from the head, which stays where it is. Past the bounds, the offset either wraps
+    // around them or gives None
+    pub fn get_at(&self, offset: isize, wrapping: bool) -> Option<u8> {
+        let index: usize = self.relative_index(offset, wrapping)?;
+        self.record_access(index..(index + 1), 0..0);
+        return Some(self.bytes[index]);
+    }
+
+    // Leaves every cell untouched and returns None if the cell is out of reach
+    pub fn set_at(&mut self, offset: isize, value: u8, wrapping: bool) -> Option<()> {
+        let index: usize = self.relative_index(offset, wrapping)?;
+        self.bytes[index] = value;
+        self.mark_written_at(index);
+        return Some(());
+    }
+
+    // Copies the current cell to the one `offset` cells away without moving the head. Leaves every cell untouched
+    // and returns None if that cell is out of reach
+    pub fn copy_relative(&mut self, offset: isize, wrapping: bool) -> Option<()> {
+        return self.set_at(offset, self.get(), wrapping);
     }
 
     pub fn goto(&mut self, location: usize) -> () {
         self.pointer = location;
+        self.record_move();
+    }
+
+    // Leaves the pointer where it is and returns None if the location can't be reached
+    pub fn checked_goto(&mut self, location: usize) -> Option<()> {
+        if !self.bounds().contains(&location) {
+            return None;
+        }
+        self.pointer = location;
+        self.record_move();
+        return Some(());
+    }
+
+    pub fn checksum(&self) -> u64 {
+        return fnv1a(&self.bytes);
+    }
+
+    pub fn snapshot(&self) -> RegionSnapshot {
+        return RegionSnapshot {
+            name: self.name.clone(),
+            bytes: self.bytes.to_vec(),
+            pointer: self.pointer,
+        };
+    }
+
+    pub fn iter(&self) -> Iter<'_, u8> {
+        return self.bytes.iter();
+    }
+
+    pub fn len(&self) -> usize {
+        return self.bytes.len();
+    }
+
+    // Always false, since a region's size is never zero
+    pub fn is_empty(&self) -> bool {
+        return self.bytes.is_empty();
+    }
+
+    pub fn view(&self) -> RegionView<'_> {
+        return RegionView {
+            name: &self.name,
+            bytes: &self.bytes,
+            pointer: self.pointer,
+        };
+    }
+
+    pub fn pointer(&self) -> usize {
+        return self.pointer;
     }
 
     pub fn get(&self) -> u8 {
+        self.record_access(self.pointer..(self.pointer + 1), 0..0);
         return self.bytes[self.pointer];
     }
 
     pub fn set(&mut self, value: u8) -> () {
         self.bytes[self.pointer] = value;
+        self.mark_written();
+    }
+
+    // By index rather than through the head, which stays where it is
+    pub fn set_cell(&mut self, index: usize, value: u8) -> () {
+        self.bytes[index] = value;
+        self.mark_written_at(index);
     }
 
     pub fn increment(&mut self) -> () {
+        self.record_access(self.pointer..(self.pointer + 1), 0..0);
         self.bytes[self.pointer] = u8::wrapping_add(self.bytes[self.pointer], 1);
+        self.mark_written();
     }
 
     pub fn decrement(&mut self) -> () {
+        self.record_access(self.pointer..(self.pointer + 1), 0..0);
         self.bytes[self.pointer] = u8::wrapping_sub(self.bytes[self.pointer], 1);
+        self.mark_written();
+    }
+
+    // Leaves the cell untouched and returns None if the operation would wrap
+    pub fn checked_increment(&mut self) -> Option<()> {
+        self.record_access(self.pointer..(self.pointer + 1), 0..0);
+        self.bytes[self.pointer] = u8::checked_add(self.bytes[self.pointer], 1)?;
+        self.mark_written();
+        return Some(());
+    }
+
+    pub fn checked_decrement(&mut self) -> Option<()> {
+        self.record_access(self.pointer..(self.pointer + 1), 0..0);
+        self.bytes[self.pointer] = u8::checked_sub(self.bytes[self.pointer], 1)?;
+        self.mark_written();
+        return Some(());
+    }
+
+    // Every cell whose value differs, as (index, value here, value in other). Cells past the end of the shorter
+    // region count as zero
+    pub fn diff(&self, other: &Region) -> Vec<(usize, u8, u8)> {
+        let length: usize = usize::max(self.bytes.len(), other.bytes.len());
+        return (0..length)
+            .map(|i| (i, self.bytes.get(i).copied().unwrap_or(0), other.bytes.get(i).copied().unwrap_or(0)))
+            .filter(|(_, old, new)| old != new)
+            .collect();
+    }
+}
+
+// Only the contents and the read/write head, so regions can be compared whatever they're called
+impl PartialEq for Region {
+    fn eq(&self, other: &Region) -> bool {
+        return (self.bytes == other.bytes) && (self.pointer == other.pointer);
+    }
+}
+
+impl Eq for Region {}
+
+impl Index<usize> for Region {
+    type Output = u8;
+
+    fn index(&self, index: usize) -> &u8 {
+        return &self.bytes[index];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotating_moves_written_flags_with_the_cells() {
+        let mut region: Region = Region::new("main", NonZeroUsize::new(4).unwrap());
+        region.poison();
+        region.set(1);
+        region.rotate(1);
+        assert!(!region.is_initialized());
+        region.right();
+        assert!(region.is_initialized());
+        assert_eq!(region.get(), 1);
+        region.rotate(-1);
+        region.left();
+        assert!(region.is_initialized());
+        assert_eq!(region.first_uninitialized(), Some(1));
+    }
+
+    #[test]
+    fn clearing_resets_cells_and_head() {
+        let mut region: Region = Region::new("main", NonZeroUsize::new(4).unwrap());
+        region.set(7);
+        region.right();
+        region.right();
+        region.set(9);
+        region.clear();
+        assert_eq!(region.pointer(), 0);
+        assert!(region.iter().all(|cell| *cell == 0));
+        region.poison();
+        region.set(1);
+        region.right();
+        region.clear();
+        assert_eq!(region.pointer(), 0);
+        assert!(region.iter().all(|cell| *cell == POISON));
+        assert_eq!(region.first_uninitialized(), Some(0));
+    }
+
+    #[test]
+    fn clearing_a_window_puts_the_head_on_its_first_cell() {
+        let mut region: Region = Region::new("main", NonZeroUsize::new(8).unwrap());
+        region.set_window(Some(2..6)).unwrap();
+        region.right();
+        region.clear();
+        assert_eq!(region.pointer(), 2);
+    }
+
+    fn counting_region() -> Region {
+        let mut region: Region = Region::new("main", NonZeroUsize::new(4).unwrap());
+        for value in 0..4 {
+            region.set(value);
+            region.right();
+        }
+        region.goto(1);
+        return region;
+    }
+
+    #[test]
+    fn offsets_within_the_region() {
+        let region: Region = counting_region();
+        assert_eq!(region.get_at(0, false), Some(1));
+        assert_eq!(region.get_at(2, false), Some(3));
+        assert_eq!(region.get_at(-1, false), Some(0));
+        assert_eq!(region.pointer(), 1);
+    }
+
+    #[test]
+    fn offsets_past_the_ends_wrap_or_fail() {
+        let mut region: Region = counting_region();
+        assert_eq!(region.get_at(3, false), None);
+        assert_eq!(region.get_at(-2, false), None);
+        assert_eq!(region.get_at(3, true), Some(0));
+        assert_eq!(region.get_at(-2, true), Some(3));
+        assert_eq!(region.get_at(-9, true), Some(0));
+        assert_eq!(region.set_at(-2, 9, false), None);
+        assert_eq!(region.iter().copied().collect::<Vec<u8>>(), [0, 1, 2, 3]);
+        assert_eq!(region.set_at(-2, 9, true), Some(()));
+        assert_eq!(region.iter().copied().collect::<Vec<u8>>(), [0, 1, 2, 9]);
+        assert_eq!(region.pointer(), 1);
+    }
+
+    #[test]
+    fn offsets_inside_a_window_stay_inside_it() {
+        let mut region: Region = counting_region();
+        region.set_window(Some(1..3)).unwrap();
+        assert_eq!(region.get_at(1, false), Some(2));
+        assert_eq!(region.get_at(2, false), None);
+        assert_eq!(region.get_at(2, true), Some(1));
+        assert_eq!(region.get_at(-1, true), Some(2));
     }
 }